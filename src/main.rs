@@ -1,129 +1,543 @@
 mod utils;
 
 use clap::Parser;
-use noodles_sam::header::record::value::map::Inner;
-use crate::utils::filter::Filter;
-use std::{collections::HashMap, env, path::{Path, PathBuf}};
-use crate::utils::alignment_handler;
-use std::any::type_name;
-use std::fs;
-use crate::utils::normalizer::{cpm, rpkm, rpgc, bpm};
-use bigtools::{BigWigWrite, Value};
-use bigtools::beddata::BedParserStreamingIterator;
-use bigtools::bed::bedparser::BedIteratorStream;
+use crate::utils::filter::{Filter, MatePolicy, PairFilter, StrandSelection};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use crate::utils::coverage_bins::{BinStorage, CoverageBins, DenseBins, RunLengthBins, SparseBins};
+use crate::utils::coverage_options::{AtacShift, CountMode, CoverageOptions, LibraryType};
+use crate::utils::intervals::{BedBins, IntervalSet, zero_masked_bed_bins, zero_masked_bins};
+use crate::utils::alignment_handler::{Alignment, AlignmentFormat, AlignmentIndex, detect_alignment_format};
+use crate::utils::normalizer::{normalize, rpkm_by_width, LibrarySize, Normalization};
+use crate::utils::output::{BinLayout, ChromosomeBins, OutputFormat, chromosome_write_order, track_output_path, write_bigwig_output, write_bedgraph_output, write_tsv_output};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Cli {
-    /// Path to the bam file
+    /// Path to the bam or cram file, the format is detected from the file
     #[arg(short, long)]
     bam_file_path: PathBuf,
     #[arg(short, long)]
     index_file_path: PathBuf,
-    #[arg(long, default_value_t = 50)]
-    bin_size: u16,
+    /// Reference FASTA (with .fai) used to decode reference-compressed CRAM
+    #[arg(short, long)]
+    reference: Option<PathBuf>,
+    /// Bin size in bp, up to 4294967295 (megabase bins for copy number or compartments are fine)
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u32).range(1..))]
+    bin_size: u32,
+    /// Sliding window width in bp, each --step-size interval carries the value of the window centred on it
+    #[arg(long, requires = "step_size", conflicts_with = "bin_size", value_parser = clap::value_parser!(u32).range(1..))]
+    window_size: Option<u32>,
+    /// Step of the sliding windows in bp, coverage is counted at this resolution. The window must be a multiple of it
+    #[arg(long, requires = "window_size", value_parser = clap::value_parser!(u32).range(1..))]
+    step_size: Option<u32>,
+    /// BED of variable-width bins (sorted, non-overlapping, gaps allowed), one value per interval instead of --bin-size tiles
+    #[arg(long, conflicts_with_all = ["bin_size", "window_size", "region", "regions_bed", "merge_bins"])]
+    bins_bed: Option<PathBuf>,
+    /// Output path, "-" writes bedGraph to stdout
     #[arg(short, long, default_value = "coverage_over_bins.bed")]
     output_file: PathBuf,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Bigwig)]
+    output_format: OutputFormat,
+    /// Merge adjacent bins with equal values into one interval
+    #[arg(long, default_value_t = false)]
+    merge_bins: bool,
+    /// How bins are held in memory: dense, sparse (non-zero bins only) or run-length. `auto` picks from bin size and read density
+    #[arg(long, value_enum, default_value_t = BinStorage::Auto)]
+    bin_storage: BinStorage,
+    /// Only compute and write coverage over this region (chr, chr:start-end, 1-based). Repeatable
+    #[arg(long)]
+    region: Vec<String>,
+    /// Only compute and write coverage over the intervals of this BED file
+    #[arg(long)]
+    regions_bed: Option<PathBuf>,
+    /// BED of regions to exclude (e.g. ENCODE blacklist). Reads overlapping them, also through their extended fragment, are dropped and left out of the library size
+    #[arg(long)]
+    blacklist: Option<PathBuf>,
+    /// Also set bins touching blacklisted regions to zero
+    #[arg(long, default_value_t = false, requires = "blacklist")]
+    zero_blacklist_bins: bool,
     #[arg(short, long, default_value_t = 8)]
     threads: usize,
+    /// BGZF decompression threads per coverage worker (BAM only), on top of --threads
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    decompression_threads: u64,
     #[arg(long, default_value_t = false)]
     extend_to_fragment: bool,
-    #[arg(long, default_value = "none")]
-    normalize: String,
+    /// Build paired-end fragments from both mates, paired by read name, instead of trusting TLEN
+    #[arg(long, default_value_t = false, requires = "extend_to_fragment")]
+    pair_mates: bool,
+    /// With --pair-mates: what to do when only one mate passes the filter
+    #[arg(long, value_enum, default_value_t = MatePolicy::DropPair, requires = "pair_mates")]
+    mate_policy: MatePolicy,
+    /// Single-end fragment length in bp, reads are extended in their strand direction
+    #[arg(long, requires = "extend_to_fragment", value_parser = clap::value_parser!(u64).range(1..))]
+    fragment_length: Option<u64>,
+    /// Estimate the single-end fragment length by strand cross-correlation of read 5' ends
+    #[arg(long, default_value_t = false, requires = "extend_to_fragment", conflicts_with = "fragment_length")]
+    estimate_fragment_length: bool,
+    /// ATAC-seq Tn5 shift (+4/-5). Counts shifted cut sites, or shifted fragments
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "cut-sites", conflicts_with = "extend_to_fragment")]
+    atac_shift: Option<AtacShift>,
+    /// Count whole reads, or one position per read: 5' end, 3' end, or fragment midpoint
+    #[arg(long, visible_alias = "offset", value_enum, default_value_t = CountMode::Coverage, conflicts_with_all = ["extend_to_fragment", "atac_shift"])]
+    count_mode: CountMode,
+    /// Treat reads as coming from the opposite strand when picking the 5'/3' end
+    #[arg(long, default_value_t = false)]
+    flip_strand: bool,
+    /// Write forward and reverse strand coverage to two files (sample.fwd.bw, sample.rev.bw) in one pass
+    #[arg(long, default_value_t = false)]
+    split_strands: bool,
+    /// Library strandedness used by --split-strands to find the transcript strand from the mate flags [default: unstranded]
+    #[arg(long, value_enum, requires = "split_strands")]
+    library_type: Option<LibraryType>,
+    /// Normalization method
+    #[arg(long, value_enum, default_value_t = Normalization::None)]
+    normalize: Normalization,
+    /// Effective (mappable) genome size in bp, required by rpgc
+    #[arg(long)]
+    effective_genome_size: Option<usize>,
+    /// Library size used by cpm/rpkm/rpgc: reads that passed the filter, or the index total
+    #[arg(long, value_enum, default_value_t = LibrarySize::Filtered)]
+    library_size: LibrarySize,
     #[arg(short, long, default_value_t = false)]
     fraction_counts: bool,
+    /// Report the mean per-base depth of each bin instead of the number of reads overlapping it
+    #[arg(long, default_value_t = false, conflicts_with = "fraction_counts")]
+    mean_depth: bool,
+    /// Do not count deleted bases (CIGAR D) as covered. Introns (N) are never counted
+    #[arg(long, default_value_t = false)]
+    skip_deletions: bool,
+    /// Count soft clipped bases as covered, extending the read at its ends
+    #[arg(long, default_value_t = false)]
+    include_soft_clips: bool,
+    /// Count bases where the mates of a proper pair overlap only once. Without fragment extension only
+    #[arg(long, default_value_t = false, conflicts_with_all = ["extend_to_fragment", "atac_shift", "count_mode"])]
+    count_overlap_once: bool,
+    /// Minimum mapping quality of a read to be counted
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(0..=255))]
+    min_mapq: u32,
+    /// Drop reads with MAPQ 255 (unavailable). By default they are kept, STAR uses 255 for unique hits.
+    /// Before this flag existed MAPQ 255 reads were always dropped, pass it to get that behaviour back
+    #[arg(long, default_value_t = false)]
+    drop_unavailable_mapq: bool,
+    /// Count reads flagged as PCR/optical duplicates
+    #[arg(long, default_value_t = false)]
+    keep_duplicates: bool,
+    /// Which strand to count
+    #[arg(long, value_enum, default_value_t = StrandSelection::Both)]
+    strand: StrandSelection,
+    /// Count secondary alignments
+    #[arg(long, default_value_t = false)]
+    keep_secondary: bool,
+    /// Count supplementary alignments
+    #[arg(long, default_value_t = false)]
+    keep_supplementary: bool,
+    /// Pair filtering: strict (properly paired only), lenient (read mapped), off
+    #[arg(long, value_enum, default_value_t = PairFilter::Strict)]
+    pair_filter: PairFilter,
+    /// Drop pairs with a fragment (|TLEN|) shorter than this. Single-end reads are not affected
+    #[arg(long)]
+    min_fragment_length: Option<usize>,
+    /// Drop pairs with a fragment (|TLEN|) longer than this. Single-end reads are not affected
+    #[arg(long)]
+    max_fragment_length: Option<usize>,
 }
 
 
-fn type_of<T>(_: T) -> &'static str {
-    type_name::<T>()
+fn build_filter(args: &Cli) -> Result<Filter, Box<dyn std::error::Error>> {
+    let mut filter = Filter::default();
+    filter
+        .set_minimum_mapping_quality(args.min_mapq)
+        .set_keep_unavailable_mapping_quality(!args.drop_unavailable_mapq)
+        .set_ignore_duplicates_flag(args.keep_duplicates)
+        .set_strand_selection(args.strand.clone())
+        .set_secondary_alignment_skip(!args.keep_secondary)
+        .set_supplementary_alignment_skip(!args.keep_supplementary)
+        .set_pair_filter(args.pair_filter.clone())
+        .set_mate_policy(args.mate_policy.clone())
+        .set_minimum_fragment_length(args.min_fragment_length)
+        .set_maximum_fragment_length(args.max_fragment_length);
+    filter.validate()?;
+    Ok(filter)
+}
+
+/// Rejects option combinations that would silently be ignored or cannot work.
+fn validate_normalization(args: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    if args.normalize == Normalization::Rpgc && args.fraction_counts {
+        return Err("--normalize rpgc reports mean per-base depth, it does not work with --fraction-counts".into());
+    }
+    match (&args.normalize, args.effective_genome_size) {
+        (Normalization::Rpgc, None) => Err("--normalize rpgc requires --effective-genome-size".into()),
+        (Normalization::Rpgc, Some(0)) => Err("--effective-genome-size must be greater than 0".into()),
+        (Normalization::Rpgc, Some(_)) => Ok(()),
+        (method, Some(_)) => Err(format!("--effective-genome-size is only used by rpgc, not {:?}", method).into()),
+        (_, None) => Ok(()),
+    }
+}
+
+fn build_coverage_options(args: &Cli) -> Result<CoverageOptions, Box<dyn std::error::Error>> {
+    if args.flip_strand && !matches!(args.count_mode, CountMode::FivePrime | CountMode::ThreePrime) {
+        return Err("--flip-strand only applies to --count-mode five-prime or three-prime".into());
+    }
+    if let (Some(window_size), Some(step_size)) = (args.window_size, args.step_size) && window_size % step_size != 0 {
+        return Err(format!("--window-size {} is not a multiple of --step-size {}", window_size, step_size).into());
+    }
+    let mut options = CoverageOptions::default();
+    options
+        .set_bin_size(args.step_size.unwrap_or(args.bin_size) as usize)
+        .set_window_size(args.window_size.map(|window_size| window_size as usize))
+        .set_extend_to_fragment(args.extend_to_fragment)
+        .set_pair_mates(args.pair_mates)
+        .set_fragment_length(args.fragment_length.map(|fragment_length| fragment_length as usize))
+        .set_atac_shift(args.atac_shift.clone())
+        .set_count_mode(args.count_mode.clone())
+        .set_flip_strand(args.flip_strand)
+        .set_split_strands(args.split_strands)
+        .set_library_type(args.library_type.clone().unwrap_or(LibraryType::Unstranded))
+        .set_fraction_counts(args.fraction_counts)
+        .set_mean_depth(args.mean_depth || args.normalize == Normalization::Rpgc) // 1x is per-base depth, not reads per bin
+        .set_count_deletions(!args.skip_deletions)
+        .set_include_soft_clips(args.include_soft_clips)
+        .set_count_mate_overlap_once(args.count_overlap_once);
+    Ok(options)
+}
+
+/// Combines --region and --regions-bed. None means the whole genome.
+fn build_regions(args: &Cli, chromosome_sizes: &HashMap<String, usize>) -> Result<Option<IntervalSet>, Box<dyn std::error::Error>> {
+    if args.region.is_empty() && args.regions_bed.is_none() {
+        return Ok(None);
+    }
+    let mut regions = IntervalSet::from_region_strings(&args.region, chromosome_sizes)?;
+    if let Some(regions_bed) = &args.regions_bed {
+        regions.extend(IntervalSet::from_bed(regions_bed)?);
+    }
+    regions.validate(chromosome_sizes)?;
+    if regions.is_empty() {
+        return Err("no usable intervals in --region/--regions-bed".into());
+    }
+    eprintln!("Restricting coverage to {} bp", regions.total_length());
+    Ok(Some(regions))
+}
+
+/// Reads --bins-bed. Unlike --regions-bed every interval has to be on a chromosome of the header.
+fn load_bins_bed(args: &Cli, chromosome_sizes: &HashMap<String, usize>) -> Result<Option<Arc<BedBins>>, Box<dyn std::error::Error>> {
+    let Some(bins_bed_path) = &args.bins_bed else {
+        return Ok(None);
+    };
+    let bins_bed = BedBins::from_bed(bins_bed_path)?;
+    bins_bed.validate(chromosome_sizes)?;
+    eprintln!("Counting {} bins from {}", bins_bed.bin_count(), bins_bed_path.display());
+    Ok(Some(Arc::new(bins_bed)))
+}
+
+/// Zeroes the bins of one chromosome touching the blacklist, tiles or --bins-bed intervals.
+fn zero_blacklisted_bins<C: CoverageBins>(coverage_over_bins: &mut C, blacklist: &IntervalSet, chromosome: &str, options: &CoverageOptions){
+    match options.bins_bed() {
+        Some(bins_bed) => zero_masked_bed_bins(coverage_over_bins, bins_bed.get(chromosome), blacklist.get(chromosome)),
+        None => zero_masked_bins(coverage_over_bins, blacklist.get(chromosome), *options.bin_size()),
+    }
+}
+
+/// Reads --blacklist. Chromosomes missing from the header are common in published blacklists, so they are only reported.
+fn load_blacklist(args: &Cli, chromosome_sizes: &HashMap<String, usize>) -> Result<Option<Arc<IntervalSet>>, Box<dyn std::error::Error>> {
+    let Some(blacklist_path) = &args.blacklist else {
+        return Ok(None);
+    };
+    let mut blacklist = IntervalSet::from_bed(blacklist_path)?;
+    let unknown = blacklist.clip_to_chromosomes(chromosome_sizes);
+    if !unknown.is_empty() {
+        eprintln!("Blacklist chromosomes not in the header, ignored: {}", unknown.join(", "));
+    }
+    Ok(Some(Arc::new(blacklist)))
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Cli::parse();
-    println!("{:?}", args);
-    let bam_file_path = args.bam_file_path;
-    
-    let bam_index_file = args.index_file_path;
-    let bin_size = args.bin_size;
-    let extend_to_fragment = args.extend_to_fragment;
-    let fraction_counts = args.fraction_counts;
-    let max_threads = args.threads;
+    eprintln!("{:?}", args);
+    let filter = build_filter(&args)?;
+    validate_normalization(&args)?;
+    if args.output_format == OutputFormat::Bigwig && args.output_file.as_os_str() == "-" {
+        return Err("BigWig cannot be written to stdout, use --output-format bedgraph".into());
+    }
+    if args.output_format == OutputFormat::Tsv && args.bins_bed.is_none() {
+        return Err("--output-format tsv is a table of the --bins-bed intervals and needs --bins-bed".into());
+    }
+    if args.split_strands && args.output_file.as_os_str() == "-" {
+        return Err("--split-strands writes two files and cannot write to stdout".into());
+    }
 
     rayon::ThreadPoolBuilder::new()
-        .num_threads(max_threads)
+        .num_threads(args.threads)
         .build_global()
         .unwrap();
 
-    let filter = Filter::default();
-    let mut alignment = alignment_handler::Alignment::from_bam(
-        bam_file_path,  bam_index_file, None
-    )?;
-
-    let chromosome_names = alignment.get_chromosome_names_str()?;
-    let chromosome_sizes = alignment.get_chromosome_sizes()?;
+    let bam_file_path = args.bam_file_path.clone();
+    let bam_index_file = args.index_file_path.clone();
+    match detect_alignment_format(&bam_file_path)? {
+        AlignmentFormat::Bam => {
+            if args.reference.is_some() {
+                eprintln!("--reference is only used for CRAM input, ignoring it");
+            }
+            let alignment = Alignment::from_bam(bam_file_path, bam_index_file, None)?;
+            run_with_bin_storage(alignment, &args, filter)
+        }
+        AlignmentFormat::Cram => {
+            if args.decompression_threads > 1 {
+                eprintln!("--decompression-threads only applies to BAM input, ignoring it");
+            }
+            let alignment = Alignment::from_cram(bam_file_path, bam_index_file, args.reference.clone(), None)?;
+            run_with_bin_storage(alignment, &args, filter)
+        }
+    }
+}
 
-    let coverage_over_bins_all_chromosomes = alignment.coverage_by_bin_all(bin_size, filter, extend_to_fragment, fraction_counts)?;
-    let normalized_over_bins_all_chromosomes = cpm(coverage_over_bins_all_chromosomes, alignment.total_reads().clone()).unwrap();
-    write_bigwig_output(args.output_file, normalized_over_bins_all_chromosomes, chromosome_names, chromosome_sizes, bin_size as usize, max_threads)?;
-    
-    Ok(())
+/// Resolves `--bin-storage` and runs with the matching bin container.
+/// The read length `auto` samples is handed on, so RPGC does not sample it again.
+fn run_with_bin_storage<I>(alignment: Alignment<I>, args: &Cli, filter: Filter) -> Result<(), Box<dyn std::error::Error>>
+where I: AlignmentIndex + Sync
+{
+    let counts_single_positions = args.count_mode != CountMode::Coverage || args.atac_shift == Some(AtacShift::CutSites);
+    let mut sampled_read_length = None;
+    let bin_storage = if args.bin_storage == BinStorage::Auto && args.bins_bed.is_some() {
+        BinStorage::Dense // one bin per interval, few enough to keep them all
+    }else if args.bin_storage == BinStorage::Auto {
+        let read_length = match args.fragment_length {
+            Some(fragment_length) => fragment_length as usize,
+            None if counts_single_positions => 1, // every read adds to one bin whatever its length
+            None => *sampled_read_length.insert(alignment.average_read_length(&filter, 10_000, args.extend_to_fragment)?),
+        };
+        let genome_length = alignment.get_chromosome_sizes()?.iter().sum();
+        let bin_storage = args.bin_storage.resolve(*alignment.total_reads(), read_length, genome_length, args.step_size.unwrap_or(args.bin_size) as usize, counts_single_positions);
+        eprintln!("Bin storage: {:?}", bin_storage);
+        bin_storage
+    }else{
+        args.bin_storage.clone()
+    };
+    match bin_storage {
+        BinStorage::Dense | BinStorage::Auto => run::<I, DenseBins>(alignment, args, filter, sampled_read_length),
+        BinStorage::Sparse => run::<I, SparseBins>(alignment, args, filter, sampled_read_length),
+        BinStorage::RunLength => run::<I, RunLengthBins>(alignment, args, filter, sampled_read_length),
+    }
 }
 
+/// Picks the library size for normalization after the coverage pass.
+fn library_size<I>(alignment: &mut Alignment<I>, args: &Cli, blacklist: Option<&IntervalSet>) -> Result<u64, Box<dyn std::error::Error>>
+where I: AlignmentIndex + Sync
+{
+    if args.library_size == LibrarySize::Filtered {
+        eprintln!("Library size: {} filtered reads", alignment.filtered_reads());
+        return Ok(*alignment.filtered_reads());
+    }
+    let mut library_size = *alignment.total_reads();
+    if let Some(blacklist) = blacklist {
+        let blacklisted_reads = alignment.count_reads_in_intervals(blacklist)?;
+        eprintln!("Excluding {} blacklisted reads from the library size", blacklisted_reads);
+        library_size = library_size.saturating_sub(blacklisted_reads);
+    }
+    eprintln!("Library size: {} reads from the index", library_size);
+    Ok(library_size)
+}
 
-fn write_bigwig_output(output: PathBuf, coverage_over_bins_all_chromosomes: Vec<Vec<f64>>, chrom_names: Vec<String>, chrom_sizes: Vec<usize>, bin_size: usize, threads: usize) -> Result<(), Box<dyn std::error::Error>>{
-    
-    let runtime = tokio::runtime::Builder::new_multi_thread()
-        .worker_threads(threads)
-        .enable_all()
-        .build()?;
+/// Everything after opening the input. Generic over the index so BAM and CRAM share one code path, and over the bin container.
+/// `sampled_read_length` is the `average_read_length` sample if one was already taken.
+fn run<I, C>(mut alignment: Alignment<I>, args: &Cli, mut filter: Filter, sampled_read_length: Option<usize>) -> Result<(), Box<dyn std::error::Error>>
+where I: AlignmentIndex + Sync, C: CoverageBins
+{
+    let mut options = build_coverage_options(args)?;
+    let bin_size = *options.bin_size();
+    let extend_to_fragment = *options.extend_to_fragment();
+    let max_threads = args.threads;
+    alignment.set_decompression_threads(args.decompression_threads as usize);
+    eprintln!("Input: {} ({}, index {})", alignment.file_path().display(), alignment.file_type(), alignment.index_path().display());
 
-    let mut sorted: Vec<_> = chrom_names
+    let chromosomes: Vec<(String, usize)> = alignment.get_chromosome_names_str()?
         .into_iter()
-        .zip(chrom_sizes)
-        .zip(coverage_over_bins_all_chromosomes)
-        .map(|((name, size), coverage)| (name, size, coverage))
+        .zip(alignment.get_chromosome_sizes()?)
         .collect();
-    
-    sorted.sort_by(|a, b| a.0.cmp(&b.0));
+    let chromosome_sizes: HashMap<String, usize> = chromosomes.iter().cloned().collect();
+    let regions = build_regions(args, &chromosome_sizes)?;
+    options.set_bins_bed(load_bins_bed(args, &chromosome_sizes)?);
+    let blacklist = load_blacklist(args, &chromosome_sizes)?;
+    filter.set_blacklist(blacklist.clone());
+    eprintln!("{}", filter);
 
-    let chrom_map: HashMap<String, u32> = sorted
-        .iter()
-        .map(|(name, size, _)| (name.to_string(), *size as u32))
-        .collect();
+    if *alignment.is_pair_end() && (args.fragment_length.is_some() || args.estimate_fragment_length) {
+        eprintln!("Paired-end input, fragments come from the mates (TLEN). Ignoring the single-end fragment length");
+        options.set_fragment_length(None);
+    }else if args.estimate_fragment_length {
+        let fragment_length = alignment.estimate_fragment_length(&filter, 1_000_000, 600)?;
+        eprintln!("Fragment length: {} bp (estimated by strand cross-correlation)", fragment_length);
+        options.set_fragment_length(Some(fragment_length));
+    }else if let Some(fragment_length) = options.fragment_length() {
+        eprintln!("Fragment length: {} bp", fragment_length);
+    }
 
-    let values_iter = sorted
-        .into_iter()
-        .flat_map(|(chrom_name, _, bins)|
-        {
-            bins.into_iter()
-                .enumerate()
-                .filter(|(_, val)| *val != 0.0)
-                .map(move |(bin_idx, val)| {
-                    let start = (bin_idx as u32) * bin_size as u32;
-                    let end = start + bin_size as u32;
-                    (chrom_name.clone(), Value { start, end, value: val as f32 })
-                })
-        });
-    
+    let counts_single_positions = args.count_mode != CountMode::Coverage || args.atac_shift == Some(AtacShift::CutSites);
+    let average_read_length = if counts_single_positions && args.normalize == Normalization::Rpgc {
+        Some(1) // every read covers one base
+    }else if let Some(fragment_length) = options.fragment_length() && args.normalize == Normalization::Rpgc {
+        Some(*fragment_length) // reads are extended to this, so it is what each read covers
+    }else if args.normalize == Normalization::Rpgc {
+        let average_read_length = match sampled_read_length {
+            Some(average_read_length) => average_read_length,
+            None => alignment.average_read_length(&filter, 10_000, extend_to_fragment)?,
+        };
+        eprintln!("Average read length: {}", average_read_length);
+        Some(average_read_length)
+    }else{
+        None
+    };
 
-    let data_source = BedParserStreamingIterator::wrap_infallible_iter(values_iter, false);
-    let writer = BigWigWrite::create_file(output.to_string_lossy().to_string(), chrom_map)?;
-    writer.write(data_source, runtime)?;
+    let chromosome_order = chromosome_write_order(&args.output_format, &chromosomes);
+    let mask = blacklist.as_deref().filter(|_| args.zero_blacklist_bins);
+    // Totals over the whole genome are needed before the first chromosome can be written, they come from a first pass
+    let needs_filtered_read_count = args.normalize.needs_library_size() && args.library_size == LibrarySize::Filtered;
+    let mut total_bins_counts = vec![0.0; options.track_count()];
+    if needs_filtered_read_count {
+        eprintln!("Counting the filtered reads in a first pass");
+        alignment.count_filtered_reads(&options, &filter)?;
+    }else if args.normalize.needs_bin_total() {
+        eprintln!("Counting the {:?} totals in a first pass", args.normalize);
+        alignment.coverage_by_chromosome(&options, &filter, regions.as_ref(), &chromosome_order, |chromosome_index, tracks: Vec<C>| {
+            for (total_bins_count, mut coverage_over_bins) in total_bins_counts.iter_mut().zip(tracks) {
+                if let Some(mask) = mask {
+                    zero_blacklisted_bins(&mut coverage_over_bins, mask, &chromosomes[chromosome_index].0, &options);
+                }
+                *total_bins_count += coverage_over_bins.sum();
+            }
+            Ok(())
+        })?;
+    }
+    let library_size = if args.normalize.needs_library_size() {
+        library_size(&mut alignment, args, blacklist.as_deref())?
+    }else{
+        0
+    };
+    let output_files = if *options.split_strands() {
+        vec![track_output_path(&args.output_file, "fwd"), track_output_path(&args.output_file, "rev")]
+    }else{
+        vec![args.output_file.clone()]
+    };
+
+    // One writer thread per track. Chromosomes are normalized and handed over as soon as they are counted,
+    // the bounded channel keeps the coverage pass from running ahead of the writers.
+    let chromosomes = &chromosomes;
+    let regions = regions.as_ref();
+    let split_strands = *options.split_strands();
+    let bins_bed = options.bins_bed().clone();
+    let bins_bed = bins_bed.as_deref();
+    let layout = match bins_bed {
+        Some(bins_bed) => BinLayout::Bed(bins_bed),
+        None => BinLayout::Tiles { bin_size, merge_bins: args.merge_bins, regions },
+    };
+    std::thread::scope(|scope| -> Result<(), Box<dyn std::error::Error>> {
+        let mut senders = Vec::new();
+        let mut writers = Vec::new();
+        for output_file in output_files {
+            let (sender, receiver) = std::sync::mpsc::sync_channel::<ChromosomeBins<C>>(1);
+            senders.push(sender);
+            writers.push(scope.spawn(move || -> Result<(), String> {
+                match args.output_format {
+                    OutputFormat::Bigwig => write_bigwig_output(output_file.clone(), receiver.into_iter(), chromosomes, layout, max_threads),
+                    OutputFormat::Bedgraph => write_bedgraph_output(&output_file, receiver.into_iter(), layout),
+                    OutputFormat::Tsv => match bins_bed {
+                        Some(bins_bed) => write_tsv_output(&output_file, receiver.into_iter(), bins_bed),
+                        None => Err("--output-format tsv needs --bins-bed".into()),
+                    },
+                }.map_err(|e| e.to_string())?;
+                if split_strands {
+                    eprintln!("Wrote {}", output_file.display());
+                }
+                Ok(())
+            }));
+        }
+        let coverage_result = alignment.coverage_by_chromosome(&options, &filter, regions, &chromosome_order, |chromosome_index, tracks| {
+            let (chromosome, chromosome_length) = &chromosomes[chromosome_index];
+            for ((sender, mut coverage_over_bins), total_bins_count) in senders.iter().zip(tracks).zip(&total_bins_counts) {
+                if let Some(mask) = mask {
+                    zero_blacklisted_bins(&mut coverage_over_bins, mask, chromosome, &options);
+                }
+                let normalized_over_bins = match bins_bed {
+                    Some(bins_bed) if args.normalize == Normalization::Rpkm => {
+                        let bin_widths: Vec<usize> = bins_bed.get(chromosome).iter().map(|bin| bin.interval.len()).collect();
+                        rpkm_by_width(coverage_over_bins, library_size, &bin_widths)
+                    }
+                    _ => normalize(
+                        coverage_over_bins, &args.normalize, library_size,
+                        options.value_width(), args.effective_genome_size, average_read_length, *total_bins_count,
+                    ),
+                }.map_err(|e| e as Box<dyn std::error::Error>)?;
+                sender.send((chromosome.clone(), *chromosome_length, normalized_over_bins))
+                    .map_err(|_| "output writer stopped")?;
+            }
+            Ok(())
+        });
+        drop(senders);
+        // a writer error explains a failed send, so it goes first
+        for writer in writers {
+            writer.join().map_err(|_| "output writer panicked")??;
+        }
+        coverage_result
+    })?;
 
     Ok(())
 }
 
-fn write_bed_output(output: PathBuf, coverage_over_bins_all_chromosomes: Vec<Vec<f64>>, bin_size: usize) -> Result<(), Box<dyn std::error::Error>>{
-    let mut content = String::new();
-    
-    for (chr_idx, coverage_over_bins) in coverage_over_bins_all_chromosomes.iter().enumerate() {
-        for (bin_idx, &coverage_single_bin) in coverage_over_bins.iter().enumerate() {
-            let bin_start = bin_idx * bin_size;
-            let bin_end = bin_start + bin_size - 1;
-            content.push_str(&format!("chr{}\t{}\t{}\n", chr_idx, bin_idx, coverage_single_bin));
-        }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli(window_size: &str, step_size: &str) -> Cli {
+        Cli::try_parse_from(["bamcowig", "-b", "x.bam", "-i", "x.bam.bai", "--window-size", window_size, "--step-size", step_size]).unwrap()
     }
-    fs::write(output, content).expect("Should be able to write to `/foo/tmp`");
-    Ok(())
-}
\ No newline at end of file
+
+    #[test]
+    fn window_must_be_a_multiple_of_the_step() {
+        let error = build_coverage_options(&cli("150", "40")).unwrap_err();
+        assert!(error.to_string().contains("not a multiple"), "{}", error);
+
+        let options = build_coverage_options(&cli("150", "50")).unwrap();
+        assert_eq!(*options.bin_size(), 50); // counted at step resolution
+        assert_eq!(*options.window_size(), Some(150));
+    }
+
+    #[test]
+    fn filter_flags_reach_the_filter() {
+        let filter = build_filter(&cli("150", "50")).unwrap();
+        assert_eq!(*filter.minimum_mapping_quality(), 10);
+        assert!(*filter.keep_unavailable_mapping_quality());
+        assert!(!*filter.ignore_duplicates_flag());
+        assert!(*filter.secondary_alignment_skip() && *filter.supplementary_alignment_skip());
+        assert_eq!(*filter.pair_filter(), PairFilter::Strict);
+
+        let args = Cli::try_parse_from(["bamcowig", "-b", "x.bam", "-i", "x.bam.bai", "--min-mapq", "30", "--drop-unavailable-mapq", "--keep-duplicates",
+            "--strand", "reverse", "--keep-secondary", "--keep-supplementary", "--pair-filter", "off", "--min-fragment-length", "50", "--max-fragment-length", "500"]).unwrap();
+        let filter = build_filter(&args).unwrap();
+        assert_eq!(*filter.minimum_mapping_quality(), 30);
+        assert!(!*filter.keep_unavailable_mapping_quality());
+        assert!(*filter.ignore_duplicates_flag());
+        assert_eq!(*filter.strand_selection(), StrandSelection::Reverse);
+        assert!(!*filter.secondary_alignment_skip() && !*filter.supplementary_alignment_skip());
+        assert_eq!(*filter.pair_filter(), PairFilter::Off);
+        assert_eq!((*filter.minimum_fragment_length(), *filter.maximum_fragment_length()), (Some(50), Some(500)));
+        assert!(Cli::try_parse_from(["bamcowig", "-b", "x.bam", "-i", "x.bam.bai", "--min-mapq", "256"]).is_err());
+    }
+
+    #[test]
+    fn rpgc_reports_mean_depth() {
+        let base = ["bamcowig", "-b", "x.bam", "-i", "x.bam.bai", "--normalize", "rpgc", "--effective-genome-size", "1000"];
+        let options = build_coverage_options(&Cli::try_parse_from(base).unwrap()).unwrap();
+        assert!(*options.mean_depth());
+        let error = validate_normalization(&Cli::try_parse_from(base.iter().chain(&["--fraction-counts"])).unwrap()).unwrap_err();
+        assert!(error.to_string().contains("--fraction-counts"), "{}", error);
+    }
+
+    #[test]
+    fn mate_policy_requires_pair_mates() {
+        let base = ["bamcowig", "-b", "x.bam", "-i", "x.bam.bai", "--extend-to-fragment"];
+        assert!(Cli::try_parse_from(base.iter().chain(&["--mate-policy", "keep-mate"])).is_err());
+        assert!(Cli::try_parse_from(base.iter().chain(&["--pair-mates", "--mate-policy", "keep-mate"])).is_ok());
+        assert!(Cli::try_parse_from(base).is_ok()); // the default alone does not need it
+    }
+}
@@ -1,3 +1,7 @@
 pub mod alignment_handler;
+pub mod coverage_bins;
+pub mod coverage_options;
 pub mod filter;
-pub mod normalizer;
\ No newline at end of file
+pub mod intervals;
+pub mod normalizer;
+pub mod output;
\ No newline at end of file
@@ -3,8 +3,9 @@ use std::path::PathBuf;
 use noodles_bam::bai;
 use noodles_bgzf::VirtualPosition;
 use noodles_util::alignment as noodles_alignment;
-use noodles_core::Region;
-use noodles_cram::{crai, io::reader};
+use noodles_core::{Position, Region};
+use noodles_cram::crai;
+use noodles_fasta as fasta;
 use noodles_csi as csi;
 use noodles_csi::BinningIndex;
 use indexmap::IndexMap;
@@ -13,18 +14,80 @@ use noodles_cram::io::reader as CramReader;
 use noodles_cram::io::reader::Container as CramContainer;
 use rayon::{prelude::*};
 use std::path::Path;
+use std::io::Read;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 pub type CsiIndex = csi::binning_index::Index<IndexMap<usize, VirtualPosition>>;
+/// Bins per track of one work unit and the number of reads counted in it.
+type ChunkCoverage = Result<(Vec<ChunkBins>, u64), Box<dyn std::error::Error + Send + Sync>>;
+type Records<'r> = Box<dyn Iterator<Item = std::io::Result<Box<dyn Record>>> + 'r>;
 use getset::{Getters, Setters, MutGetters};
 use crate::Filter;
+use crate::utils::filter::{ChromosomeFilter, MatePolicy};
+use std::collections::{BTreeMap, HashMap};
+use noodles_sam::alignment::Record;
+use noodles_sam::alignment::record::cigar::op::Kind;
+use crate::utils::coverage_options::{AtacShift, CountMode, CoverageOptions};
+use crate::utils::coverage_bins::{CoverageBins, sum_windows};
+use crate::utils::intervals::{BedBin, IntervalSet};
+
+const MIN_CHUNK_LENGTH: usize = 1 << 16; // below this the index lookups cost more than the reads
+const MAX_CHUNK_LENGTH: usize = 1 << 22;
+const TN5_FORWARD_SHIFT: usize = 4; // Tn5 inserts with a 9 bp duplication, see Buenrostro et al. 2013
+const TN5_REVERSE_SHIFT: usize = 5;
+const UNBOUNDED_FRAGMENT_REACH: usize = 1000; // how far outside a requested interval fragments are looked for without --max-fragment-length
+
+#[derive(Debug, PartialEq)]
+pub enum AlignmentFormat {
+    Bam,
+    Cram,
+}
+
+/// Looks at the file magic: "CRAM", or BGZF whose decompressed data starts with "BAM\1".
+/// Other gzip files (a .sam.gz, a .bed.gz) and anything else are an error.
+pub fn detect_alignment_format(alignment_path: &Path) -> Result<AlignmentFormat, Box<dyn std::error::Error>>{
+    let mut magic = Vec::with_capacity(4);
+    std::fs::File::open(alignment_path)?.take(4).read_to_end(&mut magic)?;
+    if magic == b"CRAM"{
+        return Ok(AlignmentFormat::Cram);
+    }
+    if magic.starts_with(&[0x1f, 0x8b]){
+        let mut bam_magic = [0u8; 4];
+        let mut reader = noodles_bgzf::io::Reader::new(std::fs::File::open(alignment_path)?);
+        return match reader.read_exact(&mut bam_magic){
+            Ok(()) if &bam_magic == b"BAM\x01" => Ok(AlignmentFormat::Bam),
+            _ => Err(format!("{} is gzip compressed but not BAM", alignment_path.display()).into()),
+        };
+    }
+    Err(format!("{} is neither BAM nor CRAM", alignment_path.display()).into())
+}
+
+/// Builds the repository CRAM decoding pulls reference bases from. Without a FASTA it is empty,
+/// which is fine for BAM and for CRAMs with embedded references.
+pub fn load_reference_sequence_repository(reference_path: Option<&Path>) -> Result<fasta::Repository, Box<dyn std::error::Error>>{
+    match reference_path {
+        Some(reference_path) => {
+            let reader = fasta::io::indexed_reader::Builder::default()
+                .build_from_path(reference_path)
+                .map_err(|e| format!("Cannot open reference {} (a .fai index is required): {}", reference_path.display(), e))?;
+            let adapter = fasta::repository::adapters::IndexedReader::new(reader);
+            Ok(fasta::Repository::new(adapter))
+        }
+        None => Ok(fasta::Repository::default()),
+    }
+}
 
 pub enum CountableIndex {
     Bai(bai::Index),
     Csi(CsiIndex),
 }
 
-pub trait AlignmentIndex: Sized{
+pub trait AlignmentIndex: Sized + Send + Sync{
     fn load(index_path: &Path) -> Result<Self, Box<dyn std::error::Error>>;
     fn count_total_reads(&self) -> Result<Option<u64>,Box<dyn std::error::Error>>;
+    /// Reader for one coverage worker. It shares `index` instead of copying it, BGZF is decompressed on `decompression_threads` threads when there is more than one.
+    fn chunk_reader(index: &Arc<Self>, file_path: &Path, reference_sequence_repository: &fasta::Repository, decompression_threads: usize) -> std::io::Result<ChunkReader>;
 }
 
 impl AlignmentIndex for CountableIndex {
@@ -54,6 +117,17 @@ impl AlignmentIndex for CountableIndex {
             CountableIndex::Csi(index) => count_from_index(index),
         }
     }
+
+    fn chunk_reader(index: &Arc<Self>, file_path: &Path, _reference_sequence_repository: &fasta::Repository, decompression_threads: usize) -> std::io::Result<ChunkReader> {
+        let file = std::fs::File::open(file_path)?;
+        Ok(match std::num::NonZero::new(decompression_threads).filter(|threads| threads.get() > 1) {
+            Some(worker_count) => {
+                let bgzf_reader = noodles_bgzf::io::MultithreadedReader::with_worker_count(worker_count, file);
+                ChunkReader::MultithreadedBam(noodles_bam::io::Reader::from(bgzf_reader), Arc::clone(index))
+            }
+            None => ChunkReader::Bam(noodles_bam::io::Reader::new(file), Arc::clone(index)),
+        })
+    }
 }
 
 fn count_from_index<I>(index: &csi::binning_index::Index<I>) -> 
@@ -61,12 +135,18 @@ fn count_from_index<I>(index: &csi::binning_index::Index<I>) ->
     where I: ReferenceSequenceIndex
     {
         use csi::binning_index::index::reference_sequence::Bin;
+        use csi::binning_index::ReferenceSequence as _;
 
         let depth = index.depth();
         let metadata_bin_id = Bin::metadata_id(depth);
         let mut count: u64 = 0;
         
         for reference in index.reference_sequences() {
+            if let Some(metadata) = reference.metadata() { // noodles parses the metadata pseudo-bin out of bins() into metadata()
+                count += metadata.mapped_record_count();
+                count += metadata.unmapped_record_count();
+                continue;
+            }
             let bins = reference.bins(); // Look for the metadata bin 
             if let Some(metadata_bin) = bins.get(&metadata_bin_id) { 
                 let chunks = metadata_bin.chunks(); // Metadata bin has exactly 2 chunks: // chunks[0].end = mapped count (stored as virtual position) // chunks[1].end = unmapped count 
@@ -94,9 +174,15 @@ impl AlignmentIndex for crai::Index{
         Err("No .crai index found".into())
     }
     fn count_total_reads(&self) -> Result<Option<u64>, Box<dyn std::error::Error>>{
-        println!("count of reads is not in cram.crai file. use alignment.count_total_reads()");
+        eprintln!("count of reads is not in cram.crai file. use alignment.count_total_reads()");
         Ok(None)
     }    
+    fn chunk_reader(index: &Arc<Self>, file_path: &Path, reference_sequence_repository: &fasta::Repository, _decompression_threads: usize) -> std::io::Result<ChunkReader> {
+        let reader = CramReader::Builder::default()
+            .set_reference_sequence_repository(reference_sequence_repository.clone())
+            .build_from_path(file_path)?;
+        Ok(ChunkReader::Cram(reader, Arc::clone(index)))
+    }
 }
 
 
@@ -106,366 +192,667 @@ impl AlignmentIndex for crai::Index{
 pub struct Alignment<I>{
     file_path: PathBuf,
     index_path: PathBuf,
-    reader: noodles_alignment::io::IndexedReader<std::fs::File>,
     header: noodles_sam::Header,
-    index: I,
+    index: Arc<I>, // shared with every chunk reader
     total_reads: u64,
+    filtered_reads: u64, // reads (fragments when extending paired data) that passed the filter in the last coverage pass
     file_type: String,
     is_pair_end: bool,
+    reference_sequence_repository: fasta::Repository,
+    #[getset(set = "pub")]
+    decompression_threads: usize, // BGZF workers per coverage reader, 1 decompresses on the reading thread
+}
+
+/// Reader used by the coverage workers, holding the index parsed once by `Alignment`.
+/// Every query seeks through the index, so the header is never read again.
+pub enum ChunkReader{
+    Bam(noodles_bam::io::Reader<noodles_bgzf::io::Reader<std::fs::File>>, Arc<CountableIndex>),
+    MultithreadedBam(noodles_bam::io::Reader<noodles_bgzf::io::MultithreadedReader<std::fs::File>>, Arc<CountableIndex>),
+    Cram(noodles_cram::io::Reader<std::fs::File>, Arc<crai::Index>),
+}
+
+impl ChunkReader{
+    pub fn query<'r>(&'r mut self, header: &'r noodles_sam::Header, region: &Region) -> std::io::Result<Records<'r>>{
+        match self{
+            ChunkReader::Bam(reader, index) => query_bam(reader, header, index, region),
+            ChunkReader::MultithreadedBam(reader, index) => query_bam(reader, header, index, region),
+            ChunkReader::Cram(reader, index) => {
+                let query = reader.query(header, index, region)?;
+                Ok(Box::new(query.map(|result| result.map(|record| Box::new(record) as Box<dyn Record>))))
+            }
+        }
+    }
+}
+
+fn query_bam<'r, R>(reader: &'r mut noodles_bam::io::Reader<R>, header: &noodles_sam::Header, index: &CountableIndex, region: &Region) -> std::io::Result<Records<'r>>
+where R: noodles_bgzf::io::BufRead + noodles_bgzf::io::Seek + 'r
+{
+    let query = match index{
+        CountableIndex::Bai(index) => reader.query(header, index, region)?,
+        CountableIndex::Csi(index) => reader.query(header, index, region)?,
+    };
+    Ok(Box::new(query.records().map(|result| result.map(|record| Box::new(record) as Box<dyn Record>))))
 }
 
 
 impl Alignment<CountableIndex> {
     pub fn from_bam(alignment_path: PathBuf, index_path: PathBuf, pair_end_flag: Option<bool>) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut reader = noodles_alignment::io::indexed_reader::Builder::default()
-            .build_from_path(&alignment_path)?;
-        let header = reader.read_header()?;
         let index = CountableIndex::load(&index_path)?;
+        let reference_sequence_repository = fasta::Repository::default();
+        let mut reader = Self::build_reader(&alignment_path, &reference_sequence_repository)?;
+        let header = reader.read_header()?;
         let total_reads = index.count_total_reads()?.unwrap_or(0);
         let is_pair_end = pair_end_flag.unwrap_or(reader.records(&header).next().unwrap()?.flags()?.is_segmented());
         Ok(Alignment {
             file_path: alignment_path,
-            index_path: index_path,
-            reader,
+            index_path,
             header,
-            index,
+            index: Arc::new(index),
             total_reads,
+            filtered_reads: 0,
             file_type: "bam".to_string(),
             is_pair_end,
+            reference_sequence_repository,
+            decompression_threads: 1,
         })
         
     }
 }
 
 impl Alignment<crai::Index> {
-    pub fn from_cram(alignment_path: PathBuf, index_path: PathBuf, pair_end_flag: Option<bool>) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut reader = noodles_alignment::io::indexed_reader::Builder::default()
-            .build_from_path(&alignment_path)?;
+    pub fn from_cram(alignment_path: PathBuf, index_path: PathBuf, reference_path: Option<PathBuf>, pair_end_flag: Option<bool>) -> Result<Self, Box<dyn std::error::Error>> {
+        let index = crai::Index::load(&index_path)?;
+        let reference_sequence_repository = load_reference_sequence_repository(reference_path.as_deref())?;
+        let mut reader = Self::build_reader(&alignment_path, &reference_sequence_repository)?;
         let header = reader.read_header()?;
-        let index = crai::fs::read(&index_path)?;
         let total_reads: u64 = Self::count_from_containers(&alignment_path)?;
         let is_pair_end = pair_end_flag.unwrap_or(reader.records(&header).next().unwrap()?.flags()?.is_segmented());
         Ok(Alignment {
             file_path: alignment_path,
             index_path,
-            reader,
             header,
-            index,
+            index: Arc::new(index),
             total_reads,
+            filtered_reads: 0,
             file_type: "cram".to_string(),
             is_pair_end,
+            reference_sequence_repository,
+            decompression_threads: 1,
         })
     }
 }
 
 
-impl<I> Alignment<I>{
+impl<I> Alignment<I>
+where I: AlignmentIndex + Sync
+{
+
+    /// Reader from the start of the file, for the header and for sampling reads. It needs no index.
+    fn build_reader(file_path: &Path, reference_sequence_repository: &fasta::Repository) -> Result<noodles_alignment::io::Reader<std::fs::File>, std::io::Error>{
+        noodles_alignment::io::reader::Builder::default()
+            .set_reference_sequence_repository(reference_sequence_repository.clone())
+            .build_from_path(file_path)
+    }
 
-    fn count_by_iteration(reader: &mut noodles_alignment::io::IndexedReader<std::fs::File>, header: &noodles_sam::Header) -> Result<u64, Box<dyn std::error::Error>>{
-        let mut count  = 0u64;
-        for result in reader.records(header){
-            let _ = result?;
-            count += 1;
-        }   
+    /// Number of records overlapping `intervals`, each record counted once. Used to take blacklisted reads out of the index total.
+    pub fn count_reads_in_intervals(&self, intervals: &IntervalSet) -> Result<u64, Box<dyn std::error::Error>>{
+        let mut reader = I::chunk_reader(&self.index, &self.file_path, &self.reference_sequence_repository, self.decompression_threads)?;
+        let mut count = 0u64;
+        for (chromosome, chromosome_intervals) in intervals.iter(){
+            let mut previous_end = 0usize;
+            for interval in chromosome_intervals{
+                let region = query_region(chromosome, interval.clone()).map_err(|e| e.to_string())?;
+                for result in reader.query(&self.header, &region)?{
+                    let record = result?;
+                    let start = record.alignment_start().transpose()?.map(|p| p.get() - 1).unwrap_or(0);
+                    if start < previous_end{ // also overlaps the previous interval, already counted
+                        continue;
+                    }
+                    count += 1;
+                }
+                previous_end = interval.end;
+            }
+        }
         Ok(count)
     }
 
-    pub fn coverage_by_bin_all(& mut self, bin_size:u16, filter: Filter, extend_to_fragment: bool, fraction_counts: bool) -> Result<Vec<Vec<f64>>, Box< dyn std::error::Error>>{
-        let bin_size = bin_size as usize;
+    /// Mean read length over the first `sample_size` reads that pass the filter.
+    /// With `use_fragment_length` on paired data the mean |TLEN| is used instead, since that is what gets counted.
+    pub fn average_read_length(&self, filter: &Filter, sample_size: usize, use_fragment_length: bool) -> Result<usize, Box<dyn std::error::Error>>{
+        let use_template_length = use_fragment_length && self.is_pair_end;
+        let mut total_length = 0u64;
+        let mut sampled = 0usize;
+        let mut reader = Self::build_reader(&self.file_path, &self.reference_sequence_repository)?; // own reader, so every call samples the same reads
+        reader.read_header()?;
+        for result in reader.records(&self.header){
+            let record = result?;
+            if filter.apply(record.as_ref())?{
+                continue;
+            }
+            let length = if use_template_length {
+                let template_length = record.template_length()?;
+                if template_length <= 0 { // count each pair once, from the leftmost mate
+                    continue;
+                }
+                template_length as u64
+            }else{
+                record.sequence().len() as u64
+            };
+            total_length += length;
+            sampled += 1;
+            if sampled >= sample_size{
+                break;
+            }
+        }
+        if sampled == 0{
+            return Err("could not measure average read length: no read passed the filter".into());
+        }
+        Ok((total_length as f64 / sampled as f64).round() as usize)
+    }
+
+    /// Single-end fragment length from the strand cross-correlation of read 5' ends, as MACS and phantompeakqualtools do.
+    /// Uses the first `sample_size` reads that pass the filter. Shifts up to the read length are skipped, that peak is a mappability artefact.
+    pub fn estimate_fragment_length(&self, filter: &Filter, sample_size: usize, max_fragment_length: usize) -> Result<usize, Box<dyn std::error::Error>>{
+        let mut reader = Self::build_reader(&self.file_path, &self.reference_sequence_repository)?;
+        reader.read_header()?;
+        let chromosome_count = self.header.reference_sequences().len();
+        let mut forward_starts: Vec<Vec<usize>> = vec![Vec::new(); chromosome_count];
+        let mut reverse_starts: Vec<Vec<usize>> = vec![Vec::new(); chromosome_count];
+        let mut total_read_length = 0usize;
+        let mut sampled = 0usize;
+        for result in reader.records(&self.header){
+            let record = result?;
+            if filter.apply(record.as_ref())?{
+                continue;
+            }
+            let (Some(reference_sequence_id), Some(start), Some(end)) = (
+                record.reference_sequence_id(&self.header).transpose()?,
+                record.alignment_start().transpose()?,
+                record.alignment_end().transpose()?,
+            ) else { continue };
+            if record.flags()?.is_reverse_complemented(){
+                reverse_starts[reference_sequence_id].push(end.get() - 1); // 5' end of a reverse read, 0-based
+            }else{
+                forward_starts[reference_sequence_id].push(start.get() - 1);
+            }
+            total_read_length += end.get() - start.get() + 1;
+            sampled += 1;
+            if sampled >= sample_size{
+                break;
+            }
+        }
+        if sampled == 0{
+            return Err("could not estimate fragment length: no read passed the filter".into());
+        }
+        let minimum_shift = total_read_length / sampled + 10;
+        if minimum_shift >= max_fragment_length{
+            return Err(format!("could not estimate fragment length: reads ({} bp) are as long as the largest fragment searched", minimum_shift - 10).into());
+        }
+
+        // number of forward/reverse 5' end pairs at each distance
+        let mut correlation = vec![0f64; max_fragment_length];
+        for (forward, reverse) in forward_starts.iter_mut().zip(reverse_starts.iter_mut()){
+            reverse.sort_unstable();
+            for &forward_start in forward.iter(){
+                let first = reverse.partition_point(|&reverse_start| reverse_start < forward_start + minimum_shift);
+                for &reverse_start in reverse[first..].iter().take_while(|&&reverse_start| reverse_start < forward_start + max_fragment_length){
+                    correlation[reverse_start - forward_start] += 1.0;
+                }
+            }
+        }
+        // smoothed over 11 bp so a single noisy shift does not win, ties go to the shift with more pairs of its own
+        let (best_shift, best_score, _) = (minimum_shift..max_fragment_length)
+            .map(|shift| {
+                let window = shift.saturating_sub(5)..(shift + 6).min(max_fragment_length);
+                (shift, correlation[window].iter().sum::<f64>(), correlation[shift])
+            })
+            .fold((0, 0f64, 0f64), |best, candidate| if (candidate.1, candidate.2) > (best.1, best.2) { candidate } else { best });
+        if best_score == 0.0{
+            return Err("could not estimate fragment length: no forward and reverse reads close enough to correlate".into());
+        }
+        Ok(best_shift + 1) // the reverse 5' end is the last base of the fragment
+    }
+
+    /// Bins of the chromosomes in `chromosome_order` (header indices), handed to `sink` in that order
+    /// as (chromosome index, one bin container per track). The chunks of all chromosomes go through one queue in that order.
+    /// Each chunk is folded into its chromosome as soon as the chunks before it are in, and the chromosome is handed over
+    /// after its last chunk, so only chromosomes still being counted are held in memory. Chromosomes without requested regions come out empty.
+    /// With a window size the bins are steps holding the value of the window centred on them.
+    /// The number of counted reads is kept in `filtered_reads`.
+    pub fn coverage_by_chromosome<C, F>(&mut self, options: &CoverageOptions, filter: &Filter, regions: Option<&IntervalSet>, chromosome_order: &[usize], mut sink: F) -> Result<(), Box< dyn std::error::Error>>
+    where C: CoverageBins, F: FnMut(usize, Vec<C>) -> Result<(), Box<dyn std::error::Error>>
+    {
+        let bin_size = *options.bin_size();
 
         let refs: Vec<_> = self.header.reference_sequences()
             .iter()
-            .map(|(chr, info)| (chr.clone(), info.length().get()))
+            .map(|(chr, info)| (chr.to_string(), info.length().get()))
             .collect();
 
         let file_path = &self.file_path;
-        if extend_to_fragment{ // /extend by fragments
-            let coverage_over_bins_per_chromosome: Vec<Vec<f64>> = refs.par_iter()
-            .map(|(chromosome, chromosome_length)|{
-                let mut reader = noodles_alignment::io::indexed_reader::Builder::default()
-                .build_from_path(file_path)?;
-            reader.read_header()?;
-            Self::get_coverage_chr_with_reader_iterating_reads_extend_to_fragment(
-                &mut reader, &self.header, bin_size,
-                chromosome.to_string(), *chromosome_length, filter.clone(), self.is_pair_end, fraction_counts
-            )
-            }).collect::<Result<Vec<Vec<f64>>, _>>()
+        let index = &self.index;
+        let reference_sequence_repository = &self.reference_sequence_repository;
+        let decompression_threads = self.decompression_threads;
+        let header = &self.header;
+        let is_pair_end = self.is_pair_end;
+
+        let chunk_length = chunk_length(refs.iter().map(|(_, chromosome_length)| chromosome_length).sum(), bin_size);
+        let reach = Reach::of(options, filter, is_pair_end);
+        // (position in `chromosome_order`, chunk), chromosome after chromosome
+        let mut chunks: Vec<(usize, QuerySpan)> = Vec::new();
+        let mut pending: Vec<Mutex<PendingChromosome<C>>> = Vec::with_capacity(chromosome_order.len());
+        let mut without_chunks: Vec<bool> = Vec::with_capacity(chromosome_order.len());
+        for &chromosome_index in chromosome_order{
+            let (chromosome, chromosome_length) = refs.get(chromosome_index).ok_or("chromosome index out of range")?;
+            let chromosome_chunks = query_chunks(chromosome, *chromosome_length, options, reach, chunk_length, regions)?;
+            let first_chunk = chunks.len();
+            without_chunks.push(chromosome_chunks.is_empty());
+            chunks.extend(chromosome_chunks.into_iter().map(|chunk| (pending.len(), chunk)));
+            pending.push(Mutex::new(PendingChromosome{ tracks: None, next_chunk: first_chunk, end_chunk: chunks.len(), waiting: BTreeMap::new() }));
+        }
+        let empty_tracks = |chromosome_index: usize| -> Vec<C> {
+            let bin_count = match options.bins_bed(){
+                Some(bins_bed) => bins_bed.get(&refs[chromosome_index].0).len(),
+                None => (refs[chromosome_index].1 / bin_size) +1 ,
+            };
+            (0..options.track_count()).map(|_| C::with_bin_count(bin_count)).collect()
+        };
+        let finish_tracks = |chromosome_index: usize, mut tracks: Vec<C>| -> Vec<C> {
+            if *options.mean_depth(){
+                for coverage_over_bins in tracks.iter_mut(){
+                    match options.bins_bed(){
+                        Some(bins_bed) => divide_by_bed_bin_width(coverage_over_bins, bins_bed.get(&refs[chromosome_index].0)),
+                        None => divide_by_bin_width(coverage_over_bins, bin_size, refs[chromosome_index].1),
+                    }
+                }
+            }
+            if let Some(window_size) = options.window_size(){
+                let average = *options.mean_depth() || *options.fraction_counts();
+                tracks = tracks.into_iter()
+                    .map(|steps| sum_windows(steps, window_size / bin_size, average))
+                    .collect();
+            }
+            tracks
+        };
+
+        let readers = ReaderPool::new();
+        let counted_reads = AtomicU64::new(0);
+        // finished chromosomes, bounded so counting waits for a slow `sink`
+        let (sender, receiver) = std::sync::mpsc::sync_channel::<(usize, Vec<C>)>(rayon::current_num_threads());
+        let (chunks, pending, readers, counted_reads, empty_tracks, finish_tracks) = (&chunks, &pending, &readers, &counted_reads, &empty_tracks, &finish_tracks);
+        std::thread::scope(|scope| -> Result<(), Box<dyn std::error::Error>> {
+            let counting = scope.spawn(move || -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+                batch_chunks(chunks, chunk_length)
+                    .into_iter()
+                    .par_bridge() // batches are taken in order, so chromosomes finish roughly in order
+                    .try_for_each(|batch| readers.with_reader(|| I::chunk_reader(index, file_path, reference_sequence_repository, decompression_threads), |reader| {
+                        for chunk_index in batch{
+                            let (order_index, chunk) = &chunks[chunk_index];
+                            let (chunk_bins, counted) = Self::chunk_coverage(reader, header, chunk, filter, options, is_pair_end)?;
+                            counted_reads.fetch_add(counted, Ordering::Relaxed);
+                            let chromosome_index = chromosome_order[*order_index];
+                            let finished = pending[*order_index].lock().map_err(|_| "a coverage worker panicked")?
+                                .fold(chunk_index, chunk_bins, || empty_tracks(chromosome_index));
+                            if let Some(tracks) = finished{
+                                sender.send((*order_index, finish_tracks(chromosome_index, tracks)))
+                                    .map_err(|_| "coverage sink stopped")?;
+                            }
+                        }
+                        Ok(())
+                    }))
+            });
+
+            // chromosomes can finish out of order, `sink` gets them in order
+            let mut finished: BTreeMap<usize, Vec<C>> = BTreeMap::new();
+            let mut sink_result = Ok(());
+            let mut next = 0;
+            while next < chromosome_order.len(){
+                let chromosome_index = chromosome_order[next];
+                let tracks = match finished.remove(&next){
+                    Some(tracks) => tracks,
+                    None if without_chunks[next] => finish_tracks(chromosome_index, empty_tracks(chromosome_index)),
+                    None => match receiver.recv(){
+                        Ok((order_index, tracks)) => {
+                            finished.insert(order_index, tracks);
+                            continue;
+                        }
+                        Err(_) => break, // counting stopped on an error
+                    },
+                };
+                if let Err(e) = sink(chromosome_index, tracks){
+                    sink_result = Err(e);
+                    break;
+                }
+                next += 1;
+            }
+            drop(receiver);
+            let counting_result = counting.join().map_err(|_| "a coverage worker panicked")?;
+            sink_result?; // a failed sink is why counting stopped, so it goes first
+            counting_result.map_err(|e| e as Box<dyn std::error::Error>)
+        })?;
+        self.filtered_reads = counted_reads.load(Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The reads `coverage_by_chromosome` would count, with the same filter, blacklist and fragment ownership,
+    /// over all chromosomes but without filling any bins. Kept in `filtered_reads`.
+    /// Always the whole genome, so the library size does not shrink with `--region` or `--bins-bed`.
+    pub fn count_filtered_reads(&mut self, options: &CoverageOptions, filter: &Filter) -> Result<(), Box<dyn std::error::Error>>{
+        let mut options = options.clone();
+        options.set_count_reads_only(true).set_bins_bed(None);
+        let chromosome_sizes = self.get_chromosome_sizes()?;
+        let chunk_length = chunk_length(chromosome_sizes.iter().sum(), *options.bin_size());
+        let reach = Reach::of(&options, filter, self.is_pair_end);
+        let mut chunks: Vec<(usize, QuerySpan)> = Vec::new();
+        for (chromosome_index, (chromosome, chromosome_length)) in self.get_chromosome_names_str()?.iter().zip(chromosome_sizes).enumerate(){
+            chunks.extend(query_chunks(chromosome, chromosome_length, &options, reach, chunk_length, None)?.into_iter().map(|chunk| (chromosome_index, chunk)));
+        }
+
+        let (file_path, index, reference_sequence_repository, header) = (&self.file_path, &self.index, &self.reference_sequence_repository, &self.header);
+        let readers = ReaderPool::new();
+        self.filtered_reads = batch_chunks(&chunks, chunk_length)
+            .into_par_iter()
+            .map(|batch| readers.with_reader(|| I::chunk_reader(index, file_path, reference_sequence_repository, self.decompression_threads), |reader| {
+                chunks[batch].iter()
+                    .map(|(_, chunk)| Ok(Self::chunk_coverage(reader, header, chunk, filter, &options, self.is_pair_end)?.1))
+                    .sum()
+            }))
+            .try_reduce(|| 0, |total, counted| Ok(total + counted))
             .map_err(|e| e as Box<dyn std::error::Error>)?;
-        
-            Ok(coverage_over_bins_per_chromosome)
-            
+        Ok(())
+    }
+
+    /// Bins and counted reads of one chunk, for the counting `options` ask for.
+    fn chunk_coverage(reader: &mut ChunkReader, header: &noodles_sam::Header, chunk: &QuerySpan, filter: &Filter, options: &CoverageOptions, is_pair_end: bool) -> ChunkCoverage{
+        if let Some(atac_shift) = options.atac_shift(){
+            Self::coverage_atac_shift(reader, header, chunk, filter, options, atac_shift, is_pair_end)
+        }else if *options.count_mode() != CountMode::Coverage{
+            Self::coverage_read_positions(reader, header, chunk, filter, options, is_pair_end)
+        }else if *options.extend_to_fragment(){ // /extend by fragments
+            Self::get_coverage_chr_with_reader_iterating_reads_extend_to_fragment(reader, header, chunk, filter, options, is_pair_end)
         }else{ // just calculate aligned regions for coverage
-            let coverage_over_bins_per_chromosome: Vec<Vec<f64>> = refs.par_iter()
-            .map(|(chromosome, chromosome_length)|{
-                let mut reader = noodles_alignment::io::indexed_reader::Builder::default()
-                .build_from_path(file_path)?;
-            reader.read_header()?;
-            Self::get_coverage_chr_with_reader_iterating_reads(
-                &mut reader, &self.header, bin_size,
-                chromosome.to_string(), *chromosome_length, filter.clone(), fraction_counts
-            )
-            }).collect::<Result<Vec<Vec<f64>>, _>>()
-            .map_err(|e| e as Box<dyn std::error::Error>)?;
-        
-            Ok(coverage_over_bins_per_chromosome)
+            Self::get_coverage_chr_with_reader_iterating_reads(reader, header, chunk, filter, options)
         }
-        
     }
 
 
 
     fn get_coverage_chr_with_reader_iterating_reads_extend_to_fragment(
-        reader: &mut noodles_alignment::io::IndexedReader<std::fs::File>,
+        reader: &mut ChunkReader,
             header: &noodles_sam::Header,
-            bin_size: usize,
-            chromosome: String,
-            chromosome_length: usize,
-            filter: Filter,
+            span: &QuerySpan,
+            filter: &Filter,
+            options: &CoverageOptions,
             is_pair_end: bool,
-            fraction_counts: bool,
-        ) -> Result<Vec<f64>, Box<dyn std::error::Error + Send + Sync>> 
+        ) -> ChunkCoverage 
     {
-        let bin_count = (chromosome_length / bin_size) +1 ;
-
-
-        let region: Region = format!("{}:{}-{}", chromosome, 1, chromosome_length).parse()?; //single_chromosome
-        if is_pair_end{
-            Ok(Self::coverage_extend_to_fragment_pair_end(
-            reader, 
-            header, 
-            bin_size, 
-            filter,
-            region,
-            bin_count,
-            fraction_counts,)?)
+        if is_pair_end && *options.pair_mates(){
+            Self::coverage_paired_mates(reader, header, span, filter, options)
+        }else if is_pair_end{
+            Self::coverage_extend_to_fragment_pair_end(reader, header, span, filter, options)
         }else{
-            Ok(Self::coverage_extend_to_fragment_single_end(
-            reader, 
-            header, 
-            bin_size, 
-            filter,
-            region,
-            bin_count,
-            fraction_counts,)?)
+            Self::coverage_extend_to_fragment_single_end(reader, header, span, filter, options)
         }
         
     }
 
     fn coverage_extend_to_fragment_pair_end(
-        reader: &mut noodles_alignment::io::IndexedReader<std::fs::File>,
+        reader: &mut ChunkReader,
             header: &noodles_sam::Header,
-            bin_size: usize,
-            filter: Filter,
-            region: Region, 
-            bin_count: usize,
-            fraction_counts: bool,
-        ) -> Result<Vec<f64>, Box< dyn std::error::Error + Send + Sync>>
+            span: &QuerySpan,
+            filter: &Filter,
+            options: &CoverageOptions,
+        ) -> ChunkCoverage
     {
-        let mut coverage_over_bins:Vec<f64> = vec![0f64; bin_count];
-        reader.query(header, &region)?.map(|r| r.unwrap())
-        .filter(|record| !filter.apply(record).unwrap_or(false)).filter(|record| record.template_length().unwrap() > 0)
-        .for_each(|record| {
-
-            let start = record.alignment_start().unwrap().unwrap();
-            let template_length = record.template_length().unwrap();
-            if template_length < 0{
-                println!("sanity check!!! template length <0!! template_length: {:#?}", template_length)
-            }
-            let fragment_end = start.get() + template_length.abs() as usize;
-            let start_bin = (start.get() - 1) /  bin_size; //noodles positions are 1-based. Yikes.
-            let start_offset= (start.get() - 1) % bin_size;
-            let end_bin = std::cmp::min((fragment_end - 1) / bin_size, bin_count - 1); // Some aligners (e.g. BWA) can produce alignments that extend past the reference end. The BAM spec doesn't enforce that. Yikes.
-
-            let end_offset =  (fragment_end - 1) % bin_size;
-            let flags = record.flags().unwrap();
-            
-
-            if fraction_counts{ // Fractional calculation of coverage for the starting and ending bin of the alignment
-                if end_bin == start_bin{
-                    coverage_over_bins[start_bin] += 1.0;
-                }else{
-                    
-                    coverage_over_bins[start_bin] = (bin_size - start_offset) as f64 / bin_size as f64;
-                    coverage_over_bins[end_bin] = end_offset as f64 / bin_size as f64;
-                    if end_bin - start_bin > 1{
-                        for i in (start_bin + 1)..end_bin{
-                            coverage_over_bins[i] += 1.0;
-                        }
-                    }
+        let mut counter = ChunkCounter::new(span, filter, options);
+        for result in reader.query(header, &span.region)?{
+            let record = result?;
+            let Some(read) = counter.passing_read(record.as_ref())? else { continue };
+            let template_length = record.template_length()?;
+            if template_length <= 0{ // the fragment is counted once, from the leftmost mate
+                continue;
+            }
+            let fragment = read.start..read.start + template_length as usize;
+            if counter.claim(read.start, &read, &fragment){
+                counter.add(track_index(record.as_ref(), options)?, std::slice::from_ref(&fragment));
+            }
+        }
+        counter.finish()
+    }
+
+    /// Pairs mates by read name while streaming the chunk. The fragment runs from the leftmost start to the rightmost end of both mates,
+    /// so inconsistent TLENs do not matter. The filter is applied to each mate and the mate policy decides when only one passes.
+    /// A fragment belongs to the chunk holding its leftmost start, mates starting past the chunk are fetched with a second query.
+    fn coverage_paired_mates(
+        reader: &mut ChunkReader,
+            header: &noodles_sam::Header,
+            span: &QuerySpan,
+            filter: &Filter,
+            options: &CoverageOptions,
+        ) -> ChunkCoverage
+    {
+        let mut counter = ChunkCounter::new(span, filter, options);
+        let mut waiting_mates: HashMap<Vec<u8>, Mate> = HashMap::new();
+        let mut fragments: Vec<Mate> = Vec::new();
+        for result in reader.query(header, &span.region)?{
+            let record = result?;
+            let Some((mate, name)) = primary_mate(record.as_ref(), header, &counter.filter, options)? else { continue };
+            if mate.read.start.min(mate.mate_start) < span.skip_before{ // the previous chunk owns this fragment
+                continue;
+            }
+            let Some(name) = name else { // no mate to wait for, the read is the fragment
+                if mate.passed{
+                    fragments.push(mate);
+                }
+                continue;
+            };
+            match waiting_mates.remove::<[u8]>(name){
+                Some(first_mate) => fragments.extend(fragment_from_mates(first_mate, Some(mate), &counter.filter)),
+                None => { waiting_mates.insert(name.to_vec(), mate); }
+            }
+        }
+        let chunk_end = span_end(span);
+        if let Some(last_mate_start) = waiting_mates.values().map(|mate| mate.mate_start).filter(|&mate_start| mate_start >= chunk_end).max(){
+            let region = query_region(&span.region.name().to_string(), chunk_end..last_mate_start + 1)?;
+            for result in reader.query(header, &region)?{
+                let record = result?;
+                let Some((mate, Some(name))) = primary_mate(record.as_ref(), header, &counter.filter, options)? else { continue };
+                if mate.read.start < chunk_end{ // seen by the first query
+                    continue;
                 }
-            }else{ // Adding +1 to a bin even if the read was covering it partially
-                for i in (start_bin + 1)..end_bin{
-                            coverage_over_bins[i] += 1.0;
+                if let Some(first_mate) = waiting_mates.remove::<[u8]>(name){
+                    fragments.extend(fragment_from_mates(first_mate, Some(mate), &counter.filter));
                 }
             }
-        });
-        Ok(coverage_over_bins)
+        }
+        // mates that were never found, handled as if they had failed
+        fragments.extend(waiting_mates.into_values().filter_map(|mate| fragment_from_mates(mate, None, &counter.filter)));
+        for fragment in fragments{
+            if counter.claim(fragment.read.start.min(fragment.mate_start), &fragment.read, &fragment.read){
+                counter.add(fragment.track, std::slice::from_ref(&fragment.read));
+            }
+        }
+        counter.finish()
     }
 
     fn coverage_extend_to_fragment_single_end(
-        reader: &mut noodles_alignment::io::IndexedReader<std::fs::File>,
+        reader: &mut ChunkReader,
             header: &noodles_sam::Header,
-            bin_size: usize,
-            filter: Filter,
-            region: Region, 
-            bin_count: usize,
-            fraction_counts: bool,
-        ) -> Result<Vec<f64>, Box< dyn std::error::Error + Send + Sync>>
+            span: &QuerySpan,
+            filter: &Filter,
+            options: &CoverageOptions,
+        ) -> ChunkCoverage
     {
-        let mut coverage_over_bins:Vec<f64> = vec![0f64; bin_count];
-        reader.query(header, &region)?.map(|r| r.unwrap())
-        .filter(|record| !filter.apply(record).unwrap_or(false))
-        .for_each(|record| {
-
-            let start = record.alignment_start().unwrap().unwrap().get();
-            let end = record.alignment_end().unwrap().unwrap().get();
-            let template_length = record.template_length().unwrap();
-            let mut fragment_start: usize;
-            let mut fragment_end: usize;
-
-            if template_length < 0{
-                fragment_start = (end as i32 + template_length) as usize;
-                fragment_end = end;
-            }else{
-                fragment_start = start;
-                fragment_end = start + template_length as usize;
-            }
-            
-            let start_bin = (fragment_start - 1) /  bin_size; //noodles positions are 1-based. Yikes.
-            let start_offset= (fragment_start - 1) % bin_size;
-            let end_bin = std::cmp::min((fragment_end - 1) / bin_size, bin_count - 1); // Some aligners (e.g. BWA) can produce alignments that extend past the reference end. The BAM spec doesn't enforce that. Yikes.
-
-            let end_offset =  (fragment_end - 1) % bin_size;
-            
-            if fraction_counts{ // Fractional calculation of coverage for the starting and ending bin of the alignment
-                if end_bin == start_bin{
-                    coverage_over_bins[start_bin] += 1.0;
+        let mut counter = ChunkCounter::new(span, filter, options);
+        for result in reader.query(header, &span.region)?{
+            let record = result?;
+            let Some(read) = counter.passing_read(record.as_ref())? else { continue };
+            let template_length = record.template_length()?;
+            let fragment = if let Some(fragment_length) = options.fragment_length(){ // extend in the read direction
+                if record.flags()?.is_reverse_complemented(){
+                    read.end.saturating_sub(*fragment_length)..read.end
                 }else{
-                    
-                    coverage_over_bins[start_bin] = (bin_size - start_offset) as f64 / bin_size as f64;
-                    coverage_over_bins[end_bin] = end_offset as f64 / bin_size as f64;
-                    if end_bin - start_bin > 1{
-                        for i in (start_bin + 1)..end_bin{
-                            coverage_over_bins[i] += 1.0;
-                        }
-                    }
-                }
-            }else{ // Adding +1 to a bin even if the read was covering it partially
-                for i in (start_bin + 1)..end_bin{
-                            coverage_over_bins[i] += 1.0;
+                    read.start..read.start + fragment_length
                 }
+            }else if template_length < 0{
+                read.end.saturating_sub(template_length.unsigned_abs() as usize)..read.end
+            }else if template_length > 0{
+                read.start..read.start + template_length as usize
+            }else{ // no fragment information, count the read itself
+                read.clone()
+            };
+            if counter.claim(read.start, &read, &fragment){
+                counter.add(track_index(record.as_ref(), options)?, &[fragment]);
             }
-        });
-        Ok(coverage_over_bins)
-
-        
+        }
+        counter.finish()
     }
 
-    fn get_coverage_chr_with_reader_iterating_reads(
-        reader: &mut noodles_alignment::io::IndexedReader<std::fs::File>,
+    /// Tn5 shifted ATAC-seq signal. Strand comes from the record flags, 5' ends move +4 (forward) and -5 (reverse).
+    #[allow(clippy::too_many_arguments)]
+    fn coverage_atac_shift(
+        reader: &mut ChunkReader,
             header: &noodles_sam::Header,
-            bin_size: usize,
-            chromosome: String,
-            chromosome_length: usize,
-            filter: Filter,
-            fraction_counts: bool,
-        ) -> Result<Vec<f64>, Box<dyn std::error::Error + Send + Sync>> 
+            span: &QuerySpan,
+            filter: &Filter,
+            options: &CoverageOptions,
+            atac_shift: &AtacShift,
+            is_pair_end: bool,
+        ) -> ChunkCoverage
     {
-        let bin_count = (chromosome_length / bin_size) +1 ;
-        let mut coverage_over_bins:Vec<f64> = vec![0f64; bin_count];
-
-        let region: Region = format!("{}:{}-{}", chromosome, 1, chromosome_length).parse()?; //single_chromosome
-
-        reader.query(header, &region)?.map(|r| r.unwrap())
-        .filter(|record| !filter.apply(record).unwrap_or(false))
-        .for_each(|record| {
-            let start = record.alignment_start().unwrap().unwrap();
-            let end = record.alignment_end().unwrap().unwrap();
-            let start_bin = (start.get() - 1) /  bin_size; //noodles positions are 1-based. Yikes.
-            let start_offset= (start.get() - 1) % bin_size;
-            let end_bin = std::cmp::min((end.get() - 1) / bin_size, bin_count - 1); // Some aligners (e.g. BWA) can produce alignments that extend past the reference end. The BAM spec doesn't enforce that. Yikes.
-
-            let end_offset =  (end.get() - 1) % bin_size;
-            let flags = record.flags().unwrap();
-            
-            if fraction_counts{ // Fractional calculation of coverage for the starting and ending bin of the alignment
-                if end_bin == start_bin{
-                    coverage_over_bins[start_bin] += 1.0;
-                }else{
-                    
-                    coverage_over_bins[start_bin] = (bin_size - start_offset) as f64 / bin_size as f64;
-                    coverage_over_bins[end_bin] = end_offset as f64 / bin_size as f64;
-                    if end_bin - start_bin > 1{
-                        for i in (start_bin + 1)..end_bin{
-                            coverage_over_bins[i] += 1.0;
-                        }
+        let mut counter = ChunkCounter::new(span, filter, options);
+        for result in reader.query(header, &span.region)?{
+            let record = result?;
+            let Some(read) = counter.passing_read(record.as_ref())? else { continue };
+            let is_reverse = record.flags()?.is_reverse_complemented();
+            let shifted = match atac_shift{
+                AtacShift::CutSites => {
+                    let cut_site = if is_reverse{
+                        (read.end - 1).saturating_sub(TN5_REVERSE_SHIFT)
+                    }else{
+                        read.start + TN5_FORWARD_SHIFT
+                    };
+                    cut_site..cut_site + 1
+                }
+                AtacShift::Fragments if is_pair_end => {
+                    let template_length = record.template_length()?;
+                    if template_length <= 0{ // the fragment is counted once, from the leftmost mate
+                        continue;
                     }
+                    (read.start + TN5_FORWARD_SHIFT)..(read.start + template_length as usize).saturating_sub(TN5_REVERSE_SHIFT)
                 }
-            }else{ // Adding +1 to a bin even if the read was covering it partially
-                for i in (start_bin + 1)..end_bin{
-                            coverage_over_bins[i] += 1.0;
+                AtacShift::Fragments => {
+                    if is_reverse{
+                        read.start.saturating_sub(TN5_REVERSE_SHIFT)..read.end.saturating_sub(TN5_REVERSE_SHIFT)
+                    }else{
+                        (read.start + TN5_FORWARD_SHIFT)..(read.end + TN5_FORWARD_SHIFT)
+                    }
                 }
+            };
+            if counter.claim(read.start, &read, &shifted){
+                counter.add(track_index(record.as_ref(), options)?, std::slice::from_ref(&shifted));
             }
-            
-            
-        });
-        Ok(coverage_over_bins)
+        }
+        counter.finish()
     }
 
+    /// Every read (or fragment for `Midpoint`) adds one count at a single position picked by the count mode.
+    fn coverage_read_positions(
+        reader: &mut ChunkReader,
+            header: &noodles_sam::Header,
+            span: &QuerySpan,
+            filter: &Filter,
+            options: &CoverageOptions,
+            is_pair_end: bool,
+        ) -> ChunkCoverage
+    {
+        let mut counter = ChunkCounter::new(span, filter, options);
+        for result in reader.query(header, &span.region)?{
+            let record = result?;
+            let Some(read) = counter.passing_read(record.as_ref())? else { continue };
+            let is_reverse = record.flags()?.is_reverse_complemented() != *options.flip_strand();
+            let position = match options.count_mode(){
+                CountMode::FivePrime if is_reverse => read.end - 1,
+                CountMode::FivePrime => read.start,
+                CountMode::ThreePrime if is_reverse => read.start,
+                CountMode::ThreePrime => read.end - 1,
+                CountMode::Midpoint | CountMode::Coverage => {
+                    let template_length = record.template_length()?;
+                    if is_pair_end && template_length < 0{ // the fragment is counted once, from the leftmost mate
+                        continue;
+                    }
+                    let fragment = if template_length < 0{
+                        read.end.saturating_sub(template_length.unsigned_abs() as usize)..read.end
+                    }else if template_length > 0{
+                        read.start..read.start + template_length as usize
+                    }else{ // no fragment information, use the read itself
+                        read.clone()
+                    };
+                    fragment.start + (fragment.end - fragment.start) / 2
+                }
+            };
+            let position = position..position + 1;
+            if counter.claim(read.start, &read, &position){
+                counter.add(track_index(record.as_ref(), options)?, std::slice::from_ref(&position));
+            }
+        }
+        counter.finish()
+    }
 
-    fn get_coverage_chr_with_reader(
-            reader: &mut noodles_alignment::io::IndexedReader<std::fs::File>,
+    fn get_coverage_chr_with_reader_iterating_reads(
+        reader: &mut ChunkReader,
             header: &noodles_sam::Header,
-            bin_size: usize,
-            chromosome: String,
-            chromosome_length: usize,
-        ) -> Result<Vec<u32>, Box<dyn std::error::Error + Send + Sync>> {
-        let mut coverage_over_bins: Vec<u32> = Vec::new();
-        let mut start = 1;
-        while start < chromosome_length {
-            let end = std::cmp::min(start + bin_size, chromosome_length);
-            let region: Region = format!("{}:{}-{}", chromosome, start, end).parse()?;
-            let query = reader.query(header, &region)?;
-            let mut count = 0u32;
-            for result in query {
-                let _ = result?;
-                count += 1;
-            }
-            coverage_over_bins.push(count);
-            start += bin_size;
-        }
-        Ok(coverage_over_bins)
-    }
-    pub fn get_coverage_chr(&mut self, bin_size: usize, chromosome: String, chromosome_length: usize) -> Result<Vec<u32>, Box<dyn std::error::Error + Send + Sync>>{   
-        let mut coverage_over_bins: Vec<u32> = Vec::new();
-        let mut start = 1; //start position is 1. 0 gives error.
-        while start < chromosome_length {
-            let end = std::cmp::min(start + bin_size, chromosome_length);
-            
-            let region = format!("{}:{}-{}", chromosome, start, end);
-            coverage_over_bins.push(self.get_region_coverage(region)?);
-            //println!("{:#?}", get_chr_chunk_reads(&bam_file_path, region).unwrap());
-            start += bin_size;
-        }
-        Ok(coverage_over_bins)
-    }
-
-    pub fn get_region_coverage(&mut self, region: String) -> Result<u32, Box<dyn std::error::Error + Send + Sync>>{
-        let region_parsed: Region = region.parse()?;
-        let query = self.reader.query(&self.header, &region_parsed)?;
-        let mut count = 0u32;
-        for result in query{
-            let _ = result?;
-            count += 1;
+            span: &QuerySpan,
+            filter: &Filter,
+            options: &CoverageOptions,
+        ) -> ChunkCoverage 
+    {
+        let mut counter = ChunkCounter::new(span, filter, options);
+        let mut blocks: Vec<Range<usize>> = Vec::new(); // reused for every read
+        let mut waiting_mates: HashMap<Vec<u8>, (Vec<Range<usize>>, usize)> = HashMap::new(); // left mates overlapping their mate, held back until it arrives
+        for result in reader.query(header, &span.region)?{
+            let record = result?;
+            let Some(read) = counter.passing_read(record.as_ref())? else { continue };
+            let mate = if *options.count_mate_overlap_once() { proper_pair_mate(record.as_ref())? } else { None };
+            // no chunk of the span returns a mate starting past it, nor does that mate cover any of its bins
+            let mate = mate.filter(|(_, mate_start)| *mate_start < span.query_end);
+            let owner = match mate{ // a left mate overlapping its mate belongs to the chunk where the mate starts, so the pair meets there
+                Some((_, mate_start)) if read.contains(&mate_start) => mate_start,
+                _ => read.start,
+            };
+            if !counter.claim(owner, &read, &read){
+                continue;
+            }
+            aligned_blocks(record.as_ref(), read.start, options, &mut blocks)?;
+            let track = track_index(record.as_ref(), options)?;
+            if let Some((name, mate_start)) = mate{
+                match waiting_mates.remove::<[u8]>(name){
+                    Some((mate_blocks, mate_track)) if mate_track == track => { // the pair adds like one read, shared bases once
+                        counter.add(track, &merge_blocks(&mate_blocks, &blocks));
+                    }
+                    Some((mate_blocks, mate_track)) => { // unstranded split, mates land on different tracks
+                        counter.add(mate_track, &mate_blocks);
+                        counter.add(track, &blocks);
+                    }
+                    None if read.contains(&mate_start) => { // left mate, its mate starts inside it
+                        waiting_mates.insert(name.to_vec(), (blocks.clone(), track));
+                    }
+                    None => counter.add(track, &blocks),
+                }
+                continue;
+            }
+            counter.add(track, &blocks);
         }
-        Ok(count)
-        
+        for (mate_blocks, mate_track) in waiting_mates.into_values(){ // the other mate failed the filter or is outside the region
+            counter.add(mate_track, &mate_blocks);
+        }
+        counter.finish()
     }
 
     fn count_from_containers(alignment_path: &PathBuf) -> Result<u64, Box<dyn std::error::Error>> {
     
         let mut reader = CramReader::Builder::default()
-            .build_from_path(&alignment_path)?;
+            .build_from_path(alignment_path)?;
 
         // Required for CRAM
         reader.read_file_definition()?;
@@ -484,8 +871,6 @@ impl<I> Alignment<I>{
     pub fn get_chromosome_names_str(&self) -> Result<Vec<String>, std::io::Error>{
         let chrom_names: Vec<String> = self.header.reference_sequences()
             .keys()
-            .cloned()
-            .into_iter()
             .map(|b| b.to_string())
             .collect();
         Ok(chrom_names)
@@ -496,4 +881,1112 @@ impl<I> Alignment<I>{
         .iter().map(|(_, info)| {info.length().get()}).collect();
         Ok(chromosomes_sizes)
     }
-}
\ No newline at end of file
+}
+
+
+/// One indexed query over a chromosome, the unit of parallel work. Reads only add to bins in `bins`, so spans never count a read into the same bin twice.
+/// Over requested intervals the query reaches past `coordinates` by the `Reach` of the counting mode, a read is only taken when it
+/// or what it is counted as overlaps `coordinates`. It is counted by the first span it overlaps, so a read overlapping several spans is counted once.
+pub struct QuerySpan {
+    region: Region,
+    bins: Range<usize>,
+    coordinates: Range<usize>, // the requested part, the whole chromosome without regions
+    counted_from: usize, // reads starting before this overlap the previous span, which counted them
+    skip_before: usize,  // reads starting before this belong to the previous chunk of the same span
+    query_end: usize,    // end of the span the chunk is cut from, later chunks of it query up to here
+    interval: Option<Range<usize>>, // with --bins-bed the one variable-width bin the span fills, `bins` is then its index
+}
+
+/// How far what a read is counted as (its fragment, shifted cut site or midpoint) can lie outside the read, in bp on either side.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Reach{
+    before: usize,
+    after: usize,
+}
+
+impl Reach{
+    /// Reach of the counting mode. Paired fragments start at their leftmost mate, single-end ones extend either way.
+    /// Unknown fragment lengths are bounded by `--max-fragment-length`, or `UNBOUNDED_FRAGMENT_REACH` without it.
+    fn of(options: &CoverageOptions, filter: &Filter, is_pair_end: bool) -> Reach{
+        let fragment_length = filter.maximum_fragment_length().unwrap_or(UNBOUNDED_FRAGMENT_REACH);
+        let fragments = |before, after| if is_pair_end { Reach{ before: fragment_length, after: 0 } } else { Reach{ before, after } };
+        match (options.atac_shift(), options.count_mode()){
+            (Some(AtacShift::CutSites), _) => Reach{ before: TN5_FORWARD_SHIFT, after: TN5_REVERSE_SHIFT },
+            (Some(AtacShift::Fragments), _) => fragments(TN5_FORWARD_SHIFT, TN5_REVERSE_SHIFT),
+            (None, CountMode::Midpoint) => fragments(fragment_length, fragment_length),
+            (None, CountMode::Coverage) if *options.extend_to_fragment() => {
+                let single_end_length = options.fragment_length().unwrap_or(fragment_length);
+                fragments(single_end_length, single_end_length)
+            }
+            _ => Reach::default(),
+        }
+    }
+}
+
+/// Queries of one chromosome: one per `--bins-bed` bin, otherwise its spans cut into chunks.
+fn query_chunks(chromosome: &str, chromosome_length: usize, options: &CoverageOptions, reach: Reach, chunk_length: usize, regions: Option<&IntervalSet>) -> Result<Vec<QuerySpan>, Box<dyn std::error::Error>>{
+    match options.bins_bed(){
+        Some(bins_bed) => bed_bin_spans(chromosome, chromosome_length, bins_bed.get(chromosome), reach),
+        None => chromosome_chunks(chromosome, chromosome_length, *options.bin_size(), reach, chunk_length, regions),
+    }.map_err(|e| e as Box<dyn std::error::Error>)
+}
+
+/// Splits a chromosome into queries. Without regions it is the whole chromosome,
+/// otherwise one query per group of requested intervals that touch the same bins, widened by `reach`.
+fn chromosome_spans(chromosome: &str, chromosome_length: usize, bin_size: usize, reach: Reach, regions: Option<&IntervalSet>) -> Result<Vec<QuerySpan>, Box<dyn std::error::Error + Send + Sync>>{
+    let bin_count = (chromosome_length / bin_size) +1 ;
+    let Some(regions) = regions else {
+        return Ok(vec![QuerySpan { region: query_region(chromosome, 0..chromosome_length)?, bins: 0..bin_count, coordinates: 0..chromosome_length, counted_from: 0, skip_before: 0, query_end: chromosome_length, interval: None }]);
+    };
+    let mut groups: Vec<(Range<usize>, Range<usize>)> = Vec::new(); // (coordinates, bins)
+    for interval in regions.get(chromosome) {
+        let bins = (interval.start / bin_size)..((interval.end - 1) / bin_size + 1);
+        match groups.last_mut() {
+            Some((coordinates, group_bins)) if bins.start < group_bins.end => {
+                coordinates.end = interval.end;
+                group_bins.end = bins.end;
+            }
+            _ => groups.push((interval.clone(), bins)),
+        }
+    }
+    let mut spans: Vec<QuerySpan> = Vec::with_capacity(groups.len());
+    for (coordinates, bins) in groups {
+        let query = coordinates.start.saturating_sub(reach.before)..(coordinates.end + reach.after).min(chromosome_length);
+        let counted_from = spans.last().map(|span| span.coordinates.end).unwrap_or(0);
+        spans.push(QuerySpan { region: query_region(chromosome, query.clone())?, bins, coordinates, counted_from, skip_before: 0, query_end: query.end, interval: None });
+    }
+    Ok(spans)
+}
+
+/// Cuts every span of the chromosome into chunks at multiples of `chunk_length`, a multiple of the bin size.
+/// A read is handled by the chunk it starts in and may add to bins of the whole span.
+fn chromosome_chunks(chromosome: &str, chromosome_length: usize, bin_size: usize, reach: Reach, chunk_length: usize, regions: Option<&IntervalSet>) -> Result<Vec<QuerySpan>, Box<dyn std::error::Error + Send + Sync>>{
+    let mut chunks: Vec<QuerySpan> = Vec::new();
+    for span in chromosome_spans(chromosome, chromosome_length, bin_size, reach, regions)?{
+        let (span_start, span_end) = (span_start(&span), span_end(&span));
+        let mut chunk_start = span_start;
+        while chunk_start < span_end{
+            let chunk_end = ((chunk_start / chunk_length + 1) * chunk_length).min(span_end);
+            let first_chunk = chunk_start == span_start;
+            chunks.push(QuerySpan{
+                region: query_region(chromosome, chunk_start..chunk_end)?,
+                bins: span.bins.clone(),
+                coordinates: span.coordinates.clone(),
+                counted_from: span.counted_from,
+                skip_before: if first_chunk { span.skip_before } else { chunk_start },
+                query_end: span.query_end,
+                interval: None,
+            });
+            chunk_start = chunk_end;
+        }
+    }
+    Ok(chunks)
+}
+
+/// One query per `--bins-bed` interval, widened by `reach`. Every read overlapping it adds to that bin only, so reads spanning several bins are fetched once per bin.
+fn bed_bin_spans(chromosome: &str, chromosome_length: usize, bins: &[BedBin], reach: Reach) -> Result<Vec<QuerySpan>, Box<dyn std::error::Error + Send + Sync>>{
+    let mut spans: Vec<QuerySpan> = Vec::with_capacity(bins.len());
+    for (bin_index, bin) in bins.iter().enumerate(){
+        let query = bin.interval.start.saturating_sub(reach.before)..(bin.interval.end + reach.after).min(chromosome_length);
+        let counted_from = spans.last().map(|span| span.coordinates.end).unwrap_or(0);
+        spans.push(QuerySpan{
+            region: query_region(chromosome, query.clone())?,
+            bins: bin_index..bin_index + 1,
+            coordinates: bin.interval.clone(),
+            counted_from,
+            skip_before: 0,
+            query_end: query.end,
+            interval: Some(bin.interval.clone()),
+        });
+    }
+    Ok(spans)
+}
+
+/// Chunk length for `genome_length` bp: about four chunks per thread, between `MIN_CHUNK_LENGTH` and `MAX_CHUNK_LENGTH`, rounded up to whole bins.
+fn chunk_length(genome_length: usize, bin_size: usize) -> usize{
+    let chunk_length = genome_length.div_ceil(rayon::current_num_threads() * 4).clamp(MIN_CHUNK_LENGTH, MAX_CHUNK_LENGTH);
+    chunk_length.div_ceil(bin_size) * bin_size
+}
+
+/// Start of the span's query, 0-based.
+fn span_start(span: &QuerySpan) -> usize{
+    span.region.interval().start().map(|start| start.get() - 1).unwrap_or(0)
+}
+
+/// End of the span's query, 0-based exclusive.
+fn span_end(span: &QuerySpan) -> usize{
+    span.region.interval().end().map(|end| end.get()).unwrap_or(usize::MAX)
+}
+
+/// 0-based half-open coordinates to a noodles region (1-based inclusive).
+fn query_region(chromosome: &str, coordinates: Range<usize>) -> Result<Region, Box<dyn std::error::Error + Send + Sync>>{
+    let start = Position::try_from(coordinates.start + 1)?;
+    let end = Position::try_from(coordinates.end.max(coordinates.start + 1))?;
+    Ok(Region::new(chromosome, start..=end))
+}
+
+/// Groups consecutive chunks into batches of at least `chunk_length` bp, so thousands of small contigs do not each become a task.
+fn batch_chunks(chunks: &[(usize, QuerySpan)], chunk_length: usize) -> Vec<Range<usize>>{
+    let mut batches: Vec<Range<usize>> = Vec::new();
+    let mut batch_start = 0;
+    let mut batch_length = 0;
+    for (index, (_, chunk)) in chunks.iter().enumerate(){
+        batch_length += span_end(chunk) - span_start(chunk);
+        if batch_length >= chunk_length{
+            batches.push(batch_start..index + 1);
+            batch_start = index + 1;
+            batch_length = 0;
+        }
+    }
+    if batch_start < chunks.len(){
+        batches.push(batch_start..chunks.len());
+    }
+    batches
+}
+
+/// One chunk reader per rayon thread, opened on first use and kept for the whole pass.
+struct ReaderPool(Vec<Mutex<Option<ChunkReader>>>);
+
+impl ReaderPool{
+    fn new() -> ReaderPool{
+        ReaderPool((0..rayon::current_num_threads()).map(|_| Mutex::new(None)).collect())
+    }
+
+    /// Runs `f` with the reader of the calling thread, opened with `open` if it has none yet.
+    fn with_reader<T>(&self, open: impl FnOnce() -> std::io::Result<ChunkReader>, f: impl FnOnce(&mut ChunkReader) -> Result<T, Box<dyn std::error::Error + Send + Sync>>) -> Result<T, Box<dyn std::error::Error + Send + Sync>>{
+        let mut reader_slot = self.0[rayon::current_thread_index().unwrap_or(0) % self.0.len()].lock().map_err(|_| "a coverage worker panicked")?;
+        let reader = match &mut *reader_slot{
+            Some(reader) => reader,
+            reader_slot @ None => reader_slot.insert(open()?),
+        };
+        f(reader)
+    }
+}
+
+/// What one chunk has counted: a bin container per track and the number of counted reads.
+/// Every counting mode passes its records through `passing_read` and `claim`, so the filter, chunk ownership,
+/// the blacklist and the read count work the same whatever a read is turned into.
+struct ChunkCounter<'a>{
+    span: &'a QuerySpan,
+    options: &'a CoverageOptions,
+    filter: ChromosomeFilter<'a>,
+    chunk_end: usize,
+    tracks: Vec<ChunkBins>,
+    counted_reads: u64,
+}
+
+impl<'a> ChunkCounter<'a>{
+    fn new(span: &'a QuerySpan, filter: &'a Filter, options: &'a CoverageOptions) -> ChunkCounter<'a>{
+        ChunkCounter{
+            span,
+            options,
+            filter: filter.on_chromosome(&span.region.name().to_string()),
+            chunk_end: span_end(span),
+            tracks: vec![ChunkBins::new(span, options); options.track_count()],
+            counted_reads: 0,
+        }
+    }
+
+    /// Aligned span of a record passing the filter, 0-based half-open. `None` for filtered and unplaced records.
+    fn passing_read(&self, record: &dyn Record) -> std::io::Result<Option<Range<usize>>>{
+        if self.filter.apply(record).unwrap_or(false){
+            return Ok(None);
+        }
+        let (Some(start), Some(end)) = (record.alignment_start().transpose()?, record.alignment_end().transpose()?) else { return Ok(None) };
+        Ok(Some((start.get() - 1)..end.get())) //noodles positions are 1-based. Yikes.
+    }
+
+    /// Whether the chunk takes `read`, counted as `extent` (fragment, shifted or single position), and counts it if so.
+    /// `owner` picks the chunk, reads owned by another chunk of the span, touching the blacklist or only fetched
+    /// because the query reaches past the span's coordinates are not taken. See `QuerySpan` for which span counts it.
+    fn claim(&mut self, owner: usize, read: &Range<usize>, extent: &Range<usize>) -> bool{
+        if owner < self.span.skip_before || owner >= self.chunk_end{
+            return false;
+        }
+        let reach = read.start.min(extent.start)..read.end.max(extent.end);
+        if reach.end <= self.span.coordinates.start || reach.start >= self.span.coordinates.end{
+            return false;
+        }
+        if self.filter.is_blacklisted(extent){
+            return false;
+        }
+        if reach.start >= self.span.counted_from{
+            self.counted_reads += 1;
+        }
+        true
+    }
+
+    /// Adds one taken read or fragment covering `blocks` to `track`, see `add_to_bins`.
+    fn add(&mut self, track: usize, blocks: &[Range<usize>]){
+        add_to_bins(&mut self.tracks[track], blocks, self.options, self.span);
+    }
+
+    fn finish(self) -> ChunkCoverage{
+        Ok((self.tracks, self.counted_reads))
+    }
+}
+
+/// Bins of a chromosome while its chunks are being counted. Chunks are folded in queue order,
+/// so the bin containers are filled front to back; chunks finishing early wait in `waiting`.
+struct PendingChromosome<C>{
+    tracks: Option<Vec<C>>, // allocated with the first folded chunk
+    next_chunk: usize, // queue index of the next chunk to fold
+    end_chunk: usize,
+    waiting: BTreeMap<usize, Vec<ChunkBins>>,
+}
+
+impl<C: CoverageBins> PendingChromosome<C>{
+    /// Adds the bins of chunk `chunk_index`, returns the tracks once every chunk is in.
+    fn fold(&mut self, chunk_index: usize, chunk_bins: Vec<ChunkBins>, empty_tracks: impl FnOnce() -> Vec<C>) -> Option<Vec<C>>{
+        self.waiting.insert(chunk_index, chunk_bins);
+        let tracks = self.tracks.get_or_insert_with(empty_tracks);
+        while let Some(chunk_bins) = self.waiting.remove(&self.next_chunk){
+            for (coverage_over_bins, chunk_bins) in tracks.iter_mut().zip(chunk_bins){
+                chunk_bins.add_to(coverage_over_bins);
+            }
+            self.next_chunk += 1;
+        }
+        if self.next_chunk == self.end_chunk { self.tracks.take() } else { None }
+    }
+}
+
+/// Bins filled by one chunk, kept as start/end deltas so adding a read costs the same whatever its length.
+/// `deltas[i]` is how much bin `offset + i` differs from the bin before it, prefix-summed once when the chunk is added to its chromosome.
+/// Deltas count reads, or covered bases with fraction counts and mean depth, so the sums stay exact integers.
+/// Contributions before `offset` (reverse extension, shifts, reads entering a region) are kept in `spill`.
+#[derive(Clone)]
+pub struct ChunkBins{
+    offset: usize,
+    deltas: Vec<i64>,
+    spill: Vec<(Range<usize>, i64)>,
+    divisor: f64, // one delta unit in bin value, 1 / bin_size for fraction counts
+    shares: BTreeMap<usize, f64>, // bin values that are no whole delta unit: shares of reads split over --bins-bed bins
+}
+
+impl ChunkBins{
+    fn new(span: &QuerySpan, options: &CoverageOptions) -> ChunkBins{
+        let bin_size = *options.bin_size();
+        let (offset, length) = if span.interval.is_some(){
+            (span.bins.start, span.bins.len())
+        }else{
+            let offset = (span_start(span) / bin_size).max(span.bins.start);
+            (offset, ((span_end(span) - 1) / bin_size + 1).saturating_sub(offset))
+        };
+        let length = if *options.count_reads_only() { 0 } else { length };
+        let divisor = if *options.fraction_counts() && !*options.mean_depth() && span.interval.is_none() { bin_size as f64 } else { 1.0 };
+        ChunkBins{ offset, deltas: vec![0; length + 1], spill: Vec::new(), divisor, shares: BTreeMap::new() }
+    }
+
+    /// What a whole read adds to each bin it touches, in delta units.
+    fn read_weight(&self) -> i64{
+        self.divisor as i64
+    }
+
+    /// Adds `value` to every bin in `bins`.
+    fn add(&mut self, bins: Range<usize>, value: i64){
+        let mut first = bins.start;
+        if first < self.offset{
+            let spill_end = bins.end.min(self.offset);
+            if first < spill_end{
+                self.spill.push((first..spill_end, value));
+            }
+            first = spill_end;
+        }
+        if first >= bins.end{
+            return;
+        }
+        let end_index = bins.end - self.offset;
+        if end_index >= self.deltas.len(){
+            self.deltas.resize(end_index + 1, 0);
+        }
+        self.deltas[first - self.offset] += value;
+        self.deltas[end_index] -= value;
+    }
+
+    /// Adds the chunk into the bins of its chromosome, front to back.
+    fn add_to<C: CoverageBins>(self, coverage_over_bins: &mut C){
+        if let Some(spill_start) = self.spill.iter().map(|(bins, _)| bins.start).min(){
+            let mut spill_deltas = vec![0i64; self.offset - spill_start + 1];
+            for (bins, value) in &self.spill{
+                spill_deltas[bins.start - spill_start] += value;
+                spill_deltas[bins.end - spill_start] -= value;
+            }
+            coverage_over_bins.add_slice(spill_start, &bin_values(&spill_deltas, self.divisor));
+        }
+        coverage_over_bins.add_slice(self.offset, &bin_values(&self.deltas, self.divisor));
+        for (bin, share) in self.shares{
+            coverage_over_bins.add(bin, share);
+        }
+    }
+}
+
+/// Prefix sums of `deltas` in bin values. The last delta only closes the bins before it.
+fn bin_values(deltas: &[i64], divisor: f64) -> Vec<f64>{
+    let mut depth = 0i64;
+    deltas[..deltas.len() - 1].iter()
+        .map(|delta| {
+            depth += delta;
+            depth as f64 / divisor
+        })
+        .collect()
+}
+
+/// Reference blocks (0-based, half-open) a read covers, walking its CIGAR from `start`.
+/// Skips (N) split blocks, deletions are bridged only with `count_deletions`, soft clips extend the ends with `include_soft_clips`.
+fn aligned_blocks(record: &dyn Record, start: usize, options: &CoverageOptions, blocks: &mut Vec<Range<usize>>) -> std::io::Result<()>{
+    blocks.clear();
+    let mut position = start;
+    let mut leading_soft_clip = 0usize;
+    for op in record.cigar().iter(){
+        let op = op?;
+        let length = op.len();
+        match op.kind(){
+            Kind::Match | Kind::SequenceMatch | Kind::SequenceMismatch => {
+                push_block(blocks, position..position + length);
+                position += length;
+            }
+            Kind::Deletion => {
+                if *options.count_deletions(){
+                    push_block(blocks, position..position + length);
+                }
+                position += length;
+            }
+            Kind::Skip => position += length,
+            Kind::SoftClip if *options.include_soft_clips() => {
+                if blocks.is_empty() && position == start{
+                    leading_soft_clip = length;
+                }else{
+                    push_block(blocks, position..position + length);
+                }
+            }
+            Kind::SoftClip | Kind::Insertion | Kind::HardClip | Kind::Pad => {}
+        }
+    }
+    if let Some(first_block) = blocks.first_mut(){
+        first_block.start = first_block.start.saturating_sub(leading_soft_clip);
+    }
+    Ok(())
+}
+
+/// One primary alignment waiting for its mate. `read` becomes the fragment span once the mates are joined.
+struct Mate{
+    read: Range<usize>,
+    mate_start: usize, // the read's own start when there is no mate on the chromosome
+    passed: bool,
+    track: usize,
+}
+
+/// A primary mapped alignment as a `Mate`, with its name when the mate is mapped on the same chromosome. Other records give `None`.
+fn primary_mate<'r>(record: &'r dyn Record, header: &noodles_sam::Header, filter: &ChromosomeFilter, options: &CoverageOptions)
+    -> std::io::Result<Option<(Mate, Option<&'r bstr::BStr>)>>
+{
+    let flags = record.flags()?;
+    if flags.is_unmapped() || flags.is_secondary() || flags.is_supplementary(){ // only primary alignments have exactly one mate
+        return Ok(None);
+    }
+    let (Some(start), Some(end)) = (record.alignment_start().transpose()?, record.alignment_end().transpose()?) else { return Ok(None) };
+    let read = (start.get() - 1)..end.get();
+    let mate_on_chromosome = flags.is_segmented() && !flags.is_mate_unmapped()
+        && record.mate_reference_sequence_id(header).transpose()? == record.reference_sequence_id(header).transpose()?;
+    let mate_start = match record.mate_alignment_start().transpose()?{
+        Some(mate_start) if mate_on_chromosome => mate_start.get() - 1,
+        _ => read.start,
+    };
+    let mate = Mate{
+        // with the mate on this chromosome the pair is judged by its rebuilt fragment, see `fragment_from_mates`, not by TLEN
+        passed: !if mate_on_chromosome { filter.apply_ignoring_template_length(record) } else { filter.apply(record) }.unwrap_or(false),
+        read,
+        mate_start,
+        track: track_index(record, options)?,
+    };
+    Ok(Some((mate, record.name().filter(|_| mate_on_chromosome))))
+}
+
+/// Joins two mates into a fragment, leftmost start to rightmost end. `None` for `second` means the mate was never seen.
+/// The fragment length bounds apply to the joined span, whichever mates the policy keeps.
+fn fragment_from_mates(first: Mate, second: Option<Mate>, filter: &ChromosomeFilter) -> Option<Mate>{
+    let fragment_length = second.as_ref().map(|second| first.read.end.max(second.read.end) - first.read.start.min(second.read.start));
+    if !filter.accepts_fragment_length(fragment_length){
+        return None;
+    }
+    let joined = |first: Mate, second: &Mate| Mate{
+        read: first.read.start.min(second.read.start)..first.read.end.max(second.read.end),
+        mate_start: first.mate_start,
+        passed: true,
+        track: first.track,
+    };
+    match (second, filter.mate_policy()){
+        (Some(second), _) if first.passed && second.passed => Some(joined(first, &second)),
+        (Some(second), MatePolicy::KeepPair) if first.passed || second.passed => Some(joined(first, &second)),
+        (Some(second), MatePolicy::KeepMate) if second.passed => Some(second),
+        (_, MatePolicy::KeepMate | MatePolicy::KeepPair) if first.passed => Some(first),
+        _ => None,
+    }
+}
+
+/// Read name and 0-based mate start of a properly paired read with its mate on the same chromosome (TLEN not 0).
+fn proper_pair_mate(record: &dyn Record) -> std::io::Result<Option<(&bstr::BStr, usize)>>{
+    if !record.flags()?.is_properly_segmented() || record.template_length()? == 0{
+        return Ok(None);
+    }
+    let (Some(name), Some(mate_start)) = (record.name(), record.mate_alignment_start().transpose()?) else { return Ok(None) };
+    Ok(Some((name, mate_start.get() - 1)))
+}
+
+/// Union of two sorted block lists, touching blocks merged.
+fn merge_blocks(first: &[Range<usize>], second: &[Range<usize>]) -> Vec<Range<usize>>{
+    let mut all: Vec<Range<usize>> = first.iter().chain(second).cloned().collect();
+    all.sort_by_key(|block| block.start);
+    let mut merged = Vec::with_capacity(all.len());
+    for block in all{
+        push_block(&mut merged, block);
+    }
+    merged
+}
+
+/// Track a read is added to: 0 unless strands are split, then 0 for the forward and 1 for the reverse transcript strand.
+fn track_index(record: &dyn Record, options: &CoverageOptions) -> std::io::Result<usize>{
+    if !*options.split_strands(){
+        return Ok(0);
+    }
+    Ok(usize::from(options.library_type().transcript_is_reverse(record.flags()?)))
+}
+
+/// Appends a block, merging it into the previous one when they touch.
+fn push_block(blocks: &mut Vec<Range<usize>>, block: Range<usize>){
+    match blocks.last_mut(){
+        Some(last) if block.start <= last.end => last.end = last.end.max(block.end),
+        _ => blocks.push(block),
+    }
+}
+
+/// Adds one read or fragment covering `blocks` (0-based, half-open, sorted) to the bins inside `bins`.
+/// With `fraction_counts` a read spanning several bins adds the covered fraction of each bin, otherwise every touched bin gets +1 once.
+/// With `mean_depth` every bin gets the number of bases covered, see `divide_by_bin_width`.
+/// Each block costs a constant number of delta updates, see `ChunkBins`.
+fn add_to_bins(coverage_over_bins: &mut ChunkBins, blocks: &[Range<usize>], options: &CoverageOptions, span: &QuerySpan){
+    let bin_size = *options.bin_size();
+    let mean_depth = *options.mean_depth();
+    let bins = &span.bins;
+    let (Some(first_block), Some(last_block)) = (blocks.first(), blocks.last()) else { return };
+    if first_block.start >= last_block.end || bins.is_empty() || *options.count_reads_only(){
+        return;
+    }
+    if let Some(interval) = &span.interval{
+        add_to_bed_bin(coverage_over_bins, blocks, options, bins.start, interval);
+        return;
+    }
+    let last_allowed_bin = bins.end - 1; // `bins` never passes the chromosome end. Some aligners (e.g. BWA) can produce alignments that extend past the reference end. The BAM spec doesn't enforce that. Yikes.
+    let start_bin = first_block.start / bin_size;
+    let end_bin = (last_block.end - 1) / bin_size;
+
+    if !mean_depth && (!*options.fraction_counts() || start_bin == end_bin){ // Adding +1 to a bin even if the read was covering it partially
+        let weight = coverage_over_bins.read_weight();
+        let mut next_bin = bins.start; // a bin shared by two blocks only counts once
+        for block in blocks.iter().filter(|block| !block.is_empty()){
+            let first = (block.start / bin_size).max(next_bin);
+            let last = ((block.end - 1) / bin_size).min(last_allowed_bin);
+            if first > last{
+                continue;
+            }
+            coverage_over_bins.add(first..last + 1, weight);
+            next_bin = last + 1;
+        }
+        return;
+    }
+    for block in blocks.iter().filter(|block| !block.is_empty()){ // Fractional calculation of coverage for the starting and ending bin of the alignment
+        let first = (block.start / bin_size).max(bins.start);
+        let last = ((block.end - 1) / bin_size).min(last_allowed_bin);
+        if first > last{
+            continue;
+        }
+        let covered = |bin: usize| {
+            let bin_start = bin * bin_size;
+            (block.end.min(bin_start + bin_size) - block.start.max(bin_start)) as i64
+        };
+        coverage_over_bins.add(first..first + 1, covered(first));
+        if last > first{
+            coverage_over_bins.add(first + 1..last, bin_size as i64); // bins in between are fully covered
+            coverage_over_bins.add(last..last + 1, covered(last));
+        }
+    }
+}
+
+/// `add_to_bins` for a `--bins-bed` bin. With `fraction_counts` a read adds the share of its aligned bases inside `interval`,
+/// so a read split over several bins adds up to one. Otherwise the bin gets +1, or the covered bases with `mean_depth`.
+fn add_to_bed_bin(coverage_over_bins: &mut ChunkBins, blocks: &[Range<usize>], options: &CoverageOptions, bin: usize, interval: &Range<usize>){
+    let overlap: usize = blocks.iter()
+        .map(|block| block.end.min(interval.end).saturating_sub(block.start.max(interval.start)))
+        .sum();
+    if overlap == 0{
+        return;
+    }
+    if *options.mean_depth(){
+        coverage_over_bins.add(bin..bin + 1, overlap as i64);
+    }else if *options.fraction_counts(){
+        let aligned: usize = blocks.iter().map(|block| block.len()).sum();
+        *coverage_over_bins.shares.entry(bin).or_insert(0.0) += overlap as f64 / aligned as f64;
+    }else{
+        coverage_over_bins.add(bin..bin + 1, coverage_over_bins.read_weight());
+    }
+}
+
+/// `divide_by_bin_width` for `--bins-bed` bins, each divided by its own width.
+fn divide_by_bed_bin_width<C: CoverageBins>(coverage_over_bins: &mut C, bins: &[BedBin]){
+    for (bin_index, bin) in bins.iter().enumerate(){
+        let value = coverage_over_bins.get(bin_index);
+        if value != 0.0{
+            coverage_over_bins.set(bin_index, value / bin.interval.len() as f64);
+        }
+    }
+}
+
+/// Turns summed covered bases into mean per-base depth. The last bin is only as wide as what is left of the chromosome.
+fn divide_by_bin_width<C: CoverageBins>(coverage_over_bins: &mut C, bin_size: usize, chromosome_length: usize){
+    if chromosome_length == 0{
+        return;
+    }
+    // only the last bin can be cut short by the chromosome end, the rest are full width
+    let last_bin = chromosome_length.saturating_sub(1) / bin_size;
+    let last_bin_width = chromosome_length - last_bin * bin_size;
+    let last_bin_value = coverage_over_bins.get(last_bin);
+    coverage_over_bins.map_values(|value| value / bin_size as f64);
+    coverage_over_bins.set(last_bin, last_bin_value / last_bin_width as f64);
+}
+
+#[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)] // a read with one aligned block
+mod tests {
+    use super::*;
+    use noodles_sam::alignment::RecordBuf;
+    use noodles_sam::alignment::io::Write as _;
+    use noodles_sam::alignment::record::cigar::Op;
+    use noodles_sam::alignment::record::{Flags, MappingQuality};
+    use crate::utils::coverage_bins::{CoverageBins, DenseBins};
+    use crate::utils::intervals::BedBins;
+
+    const HEADER: &str = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10000\n";
+
+    /// One mate of a proper pair at 0-based `start`, `length` bp, with its mate at `mate_start`.
+    fn mate(name: &str, start: usize, length: usize, mate_start: usize) -> RecordBuf {
+        let left = start <= mate_start;
+        let flags = Flags::SEGMENTED | Flags::PROPERLY_SEGMENTED | if left {
+            Flags::FIRST_SEGMENT | Flags::MATE_REVERSE_COMPLEMENTED
+        } else {
+            Flags::LAST_SEGMENT | Flags::REVERSE_COMPLEMENTED
+        };
+        let template_length = (mate_start.max(start) + length - mate_start.min(start)) as i32;
+        RecordBuf::builder()
+            .set_name(name)
+            .set_flags(flags)
+            .set_reference_sequence_id(0)
+            .set_alignment_start(Position::try_from(start + 1).unwrap())
+            .set_mapping_quality(MappingQuality::new(60).unwrap())
+            .set_cigar([Op::new(Kind::Match, length)].into_iter().collect())
+            .set_mate_reference_sequence_id(0)
+            .set_mate_alignment_start(Position::try_from(mate_start + 1).unwrap())
+            .set_template_length(if left { template_length } else { -template_length })
+            .build()
+    }
+
+    /// Single-end read at 0-based `start`, `length` bp.
+    fn read(name: &str, start: usize, length: usize, reverse: bool) -> RecordBuf {
+        RecordBuf::builder()
+            .set_name(name)
+            .set_flags(if reverse { Flags::REVERSE_COMPLEMENTED } else { Flags::empty() })
+            .set_reference_sequence_id(0)
+            .set_alignment_start(Position::try_from(start + 1).unwrap())
+            .set_mapping_quality(MappingQuality::new(60).unwrap())
+            .set_cigar([Op::new(Kind::Match, length)].into_iter().collect())
+            .build()
+    }
+
+    /// Writes `records`, sorted by start, to an indexed BAM in the temp dir and opens it. Paired or single-end is detected from the first record.
+    fn indexed_bam(name: &str, records: &[RecordBuf]) -> Alignment<CountableIndex> {
+        let header: noodles_sam::Header = HEADER.parse().unwrap();
+        let bam_path = std::env::temp_dir().join(format!("bamcowig-{}-{}.bam", std::process::id(), name));
+        let mut writer = noodles_bam::io::Writer::new(std::fs::File::create(&bam_path).unwrap());
+        writer.write_header(&header).unwrap();
+        for record in records {
+            writer.write_alignment_record(&header, record).unwrap();
+        }
+        writer.try_finish().unwrap();
+        let index_path = bam_path.with_extension("bam.bai");
+        bai::fs::write(&index_path, &noodles_bam::fs::index(&bam_path).unwrap()).unwrap();
+        Alignment::from_bam(bam_path, index_path, None).unwrap()
+    }
+
+    /// Bins of chr1 and the number of counted reads.
+    fn coverage(alignment: &mut Alignment<CountableIndex>, options: &CoverageOptions, regions: Option<&IntervalSet>) -> (Vec<f64>, u64) {
+        let mut bins = Vec::new();
+        alignment.coverage_by_chromosome(options, &Filter::default(), regions, &[0], |_, tracks: Vec<DenseBins>| {
+            bins = (0..tracks[0].bin_count()).map(|bin| tracks[0].get(bin)).collect();
+            Ok(())
+        }).unwrap();
+        (bins, *alignment.filtered_reads())
+    }
+
+    /// Positions of the non-zero 1 bp bins, each listed as often as it was counted.
+    fn counted_positions(bins: &[f64]) -> Vec<usize> {
+        bins.iter().enumerate().flat_map(|(position, count)| std::iter::repeat_n(position, *count as usize)).collect()
+    }
+
+    /// A chunk of chr1 over `chunk`, adding to `bins`.
+    fn chunk_span(chunk: Range<usize>, bins: Range<usize>) -> QuerySpan {
+        QuerySpan { region: query_region("chr1", chunk.clone()).unwrap(), bins, coordinates: 0..100, counted_from: 0, skip_before: chunk.start, query_end: 100, interval: None }
+    }
+
+    /// Bins after adding `reads` (blocks of each read) to one `ChunkBins` and folding it into `bin_count` dense bins.
+    fn chunk_values(span: &QuerySpan, options: &CoverageOptions, reads: &[&[Range<usize>]], bin_count: usize) -> Vec<f64> {
+        let mut chunk_bins = ChunkBins::new(span, options);
+        for blocks in reads {
+            add_to_bins(&mut chunk_bins, blocks, options, span);
+        }
+        let mut bins = DenseBins::with_bin_count(bin_count);
+        chunk_bins.add_to(&mut bins);
+        (0..bin_count).map(|bin| bins.get(bin)).collect()
+    }
+
+    /// The loop `ChunkBins` replaced: every bin in `bins` a read touches gets +1 once,
+    /// or with `fraction_counts` the covered share of each bin when the read spans more than one.
+    fn per_bin_loop(options: &CoverageOptions, reads: &[&[Range<usize>]], bins: Range<usize>, bin_count: usize) -> Vec<f64> {
+        let bin_size = *options.bin_size();
+        let mut values = vec![0.0; bin_count];
+        for blocks in reads {
+            let spans_bins = blocks[0].start / bin_size != (blocks[blocks.len() - 1].end - 1) / bin_size;
+            let mut touched = std::collections::BTreeSet::new();
+            for block in blocks.iter() {
+                for bin in (block.start / bin_size..=(block.end - 1) / bin_size).filter(|bin| bins.contains(bin)) {
+                    let covered = block.end.min((bin + 1) * bin_size) - block.start.max(bin * bin_size);
+                    if *options.fraction_counts() && spans_bins {
+                        values[bin] += covered as f64 / bin_size as f64;
+                    } else {
+                        touched.insert(bin);
+                    }
+                }
+            }
+            touched.into_iter().for_each(|bin| values[bin] += 1.0);
+        }
+        values
+    }
+
+    fn tiles(bin_size: usize, fraction_counts: bool) -> CoverageOptions {
+        let mut options = CoverageOptions::default();
+        options.set_bin_size(bin_size).set_fraction_counts(fraction_counts);
+        options
+    }
+
+    #[test]
+    fn aligned_blocks_follow_the_cigar() {
+        let blocks = |cigar: &[(Kind, usize)], include_soft_clips: bool, count_deletions: bool| {
+            let record = RecordBuf::builder()
+                .set_cigar(cigar.iter().map(|&(kind, length)| Op::new(kind, length)).collect())
+                .build();
+            let mut options = CoverageOptions::default();
+            options.set_include_soft_clips(include_soft_clips).set_count_deletions(count_deletions);
+            let mut blocks = Vec::new();
+            aligned_blocks(&record, 100, &options, &mut blocks).unwrap();
+            blocks
+        };
+        for include_soft_clips in [false, true] {
+            let deletion = [(Kind::Match, 10), (Kind::Deletion, 5), (Kind::Match, 10)];
+            assert_eq!(blocks(&deletion, include_soft_clips, true), [100..125]);
+            assert_eq!(blocks(&deletion, include_soft_clips, false), [100..110, 115..125]);
+            for count_deletions in [false, true] {
+                let intron = [(Kind::Match, 10), (Kind::Skip, 50), (Kind::Match, 10)];
+                assert_eq!(blocks(&intron, include_soft_clips, count_deletions), [100..110, 160..170]);
+            }
+        }
+        for count_deletions in [false, true] {
+            let soft_clips = [(Kind::SoftClip, 3), (Kind::Match, 10), (Kind::SoftClip, 4)];
+            assert_eq!(blocks(&soft_clips, true, count_deletions), [97..114]);
+            assert_eq!(blocks(&soft_clips, false, count_deletions), [100..110]);
+        }
+    }
+
+    #[test]
+    fn chunk_bins_read_inside_one_bin() {
+        for fraction_counts in [false, true] {
+            let options = tiles(10, fraction_counts);
+            let reads: &[&[Range<usize>]] = &[&[12..18], &[10..20]];
+            let values = chunk_values(&chunk_span(0..40, 0..11), &options, reads, 11);
+            assert_eq!(values, per_bin_loop(&options, reads, 0..11, 11));
+            assert_eq!(values[1], 2.0);
+        }
+    }
+
+    #[test]
+    fn chunk_bins_read_spanning_bins() {
+        let options = tiles(10, false);
+        let reads: &[&[Range<usize>]] = &[&[5..37], &[15..18, 19..23, 50..52]]; // the spliced read touches bin 1 twice
+        let values = chunk_values(&chunk_span(0..80, 0..11), &options, reads, 11);
+        assert_eq!(values, per_bin_loop(&options, reads, 0..11, 11));
+        assert_eq!(values[..6], [1.0, 2.0, 2.0, 1.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn chunk_bins_read_spilling_past_the_chunk() {
+        let options = tiles(10, false);
+        // past the end of chunk 40..80, and before its start as a reverse extended fragment would
+        let reads: &[&[Range<usize>]] = &[&[75..96], &[25..45], &[60..70]];
+        let span = chunk_span(40..80, 0..11);
+        let values = chunk_values(&span, &options, reads, 11);
+        assert_eq!(values, per_bin_loop(&options, reads, 0..11, 11));
+        assert_eq!(values, [0.0, 0.0, 1.0, 1.0, 1.0, 0.0, 1.0, 1.0, 1.0, 1.0, 0.0]);
+        // nothing is added outside the bins of the span
+        let values = chunk_values(&chunk_span(40..80, 3..8), &options, reads, 11);
+        assert_eq!(values, per_bin_loop(&options, reads, 3..8, 11));
+    }
+
+    #[test]
+    fn chunk_bins_fractional_shares() {
+        let options = tiles(10, true);
+        let reads: &[&[Range<usize>]] = &[&[5..37], &[33..35, 38..52]];
+        let values = chunk_values(&chunk_span(0..40, 0..11), &options, reads, 11);
+        let expected = per_bin_loop(&options, reads, 0..11, 11);
+        for (value, expected) in values.iter().zip(&expected) {
+            assert!((value - expected).abs() < 1e-12, "{:?} != {:?}", values, expected);
+        }
+        assert!((values[3] - 1.1).abs() < 1e-12); // 7 bp of one read and 4 bp of the other
+
+        // a read split over --bins-bed bins adds its share of aligned bases to each
+        let span = QuerySpan { region: query_region("chr1", 0..30).unwrap(), bins: 0..1, coordinates: 0..30, counted_from: 0, skip_before: 0, query_end: 30, interval: Some(0..30) };
+        let values = chunk_values(&span, &options, &[&[20..40], &[0..10]], 2);
+        assert_eq!(values, [1.5, 0.0]);
+    }
+
+    #[test]
+    fn overlapping_mate_bases_are_counted_once() {
+        let mut alignment = indexed_bam("overlap-once", &[
+            mate("overlapping", 100, 100, 150), mate("overlapping", 150, 100, 100),
+            mate("apart", 1000, 50, 1200), mate("apart", 1200, 50, 1000),
+        ]);
+        let mut options = CoverageOptions::default();
+        options.set_bin_size(500).set_mean_depth(true);
+        assert_eq!(coverage(&mut alignment, &options, None).0[..3], [200.0 / 500.0, 0.0, 100.0 / 500.0]);
+        options.set_count_mate_overlap_once(true);
+        // bases 150-200 are in both mates, the pair apart is unchanged
+        assert_eq!(coverage(&mut alignment, &options, None).0[..3], [150.0 / 500.0, 0.0, 100.0 / 500.0]);
+        options.set_mean_depth(false);
+        assert_eq!(coverage(&mut alignment, &options, None).0[..3], [1.0, 0.0, 2.0]); // the overlapping pair adds like one read
+        let _ = std::fs::remove_file(alignment.file_path());
+        let _ = std::fs::remove_file(alignment.index_path());
+    }
+
+    #[test]
+    fn overlapping_mates_straddling_a_region_end_are_counted_once() {
+        // the left mate overlaps its mate, which starts past the first region and runs into the second
+        let mut alignment = indexed_bam("region-end", &[mate("pair", 900, 110, 950), mate("pair", 950, 100, 900)]);
+        let mut options = CoverageOptions::default();
+        options.set_bin_size(100).set_count_mate_overlap_once(true);
+        let mut regions = IntervalSet::default();
+        regions.insert("chr1".to_string(), 0..940);
+        regions.insert("chr1".to_string(), 1000..1100);
+
+        let (bins, counted_reads) = coverage(&mut alignment, &options, Some(&regions));
+        assert_eq!(bins[9], 1.0); // the left mate alone, its mate is not in the first region
+        assert_eq!(bins[10], 1.0); // the pair, shared bases once
+        assert_eq!(counted_reads, 2);
+
+        let (bins, counted_reads) = coverage(&mut alignment, &options, None);
+        assert_eq!(bins[9..11], [1.0, 1.0]);
+        assert_eq!(counted_reads, 2);
+        let _ = std::fs::remove_file(alignment.file_path());
+        let _ = std::fs::remove_file(alignment.index_path());
+    }
+    #[test]
+    fn fragments_reaching_into_a_region_are_counted() {
+        // the fragment runs over 900-1150, its left mate ends before the first region
+        let mut alignment = indexed_bam("region-fragment", &[mate("pair", 900, 100, 1050), mate("pair", 1050, 100, 900)]);
+        let mut options = CoverageOptions::default();
+        options.set_extend_to_fragment(true);
+        let (bins, counted_reads) = coverage(&mut alignment, &options, None);
+        assert_eq!(bins[17..24], [0.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.0]);
+        assert_eq!(counted_reads, 1);
+
+        let mut regions = IntervalSet::default();
+        regions.insert("chr1".to_string(), 1000..1100);
+        regions.insert("chr1".to_string(), 1120..1200);
+        let (bins, counted_reads) = coverage(&mut alignment, &options, Some(&regions));
+        assert_eq!(bins[17..24], [0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 0.0]); // only bins of the regions are filled
+        assert_eq!(counted_reads, 1); // once, though both regions see it
+        let _ = std::fs::remove_file(alignment.file_path());
+        let _ = std::fs::remove_file(alignment.index_path());
+
+        // a single-end reverse read past the region, extended back into it
+        let mut alignment = indexed_bam("region-single-end", &[read("reverse", 1150, 50, true)]);
+        options.set_fragment_length(Some(200));
+        let (bins, counted_reads) = coverage(&mut alignment, &options, Some(&regions));
+        assert_eq!(bins[19..25], [0.0, 1.0, 1.0, 1.0, 1.0, 0.0]);
+        assert_eq!(counted_reads, 1);
+        let _ = std::fs::remove_file(alignment.file_path());
+        let _ = std::fs::remove_file(alignment.index_path());
+    }
+
+    #[test]
+    fn mates_paired_across_two_regions_are_counted_once() {
+        let mut unsure_mate = mate("unsure", 2900, 110, 2950);
+        *unsure_mate.mapping_quality_mut() = MappingQuality::new(0); // fails the filter
+        let mut alignment = indexed_bam("pair-mates-regions", &[
+            mate("pair", 900, 110, 950), mate("pair", 950, 100, 900),
+            unsure_mate, mate("unsure", 2950, 100, 2900),
+        ]);
+        let mut regions = IntervalSet::default();
+        for interval in [0..940, 1000..1100, 2000..2940, 3000..3100] {
+            regions.insert("chr1".to_string(), interval);
+        }
+        let mut options = CoverageOptions::default();
+        options.set_bin_size(100).set_extend_to_fragment(true).set_pair_mates(true);
+        for (mate_policy, unsure_bins) in [(MatePolicy::DropPair, [0.0, 0.0]), (MatePolicy::KeepMate, [0.0, 1.0]), (MatePolicy::KeepPair, [1.0, 1.0])] {
+            let mut filter = Filter::default();
+            filter.set_mate_policy(mate_policy.clone());
+            let mut bins = Vec::new();
+            alignment.coverage_by_chromosome(&options, &filter, Some(&regions), &[0], |_, tracks: Vec<DenseBins>| {
+                bins = (0..tracks[0].bin_count()).map(|bin| tracks[0].get(bin)).collect();
+                Ok(())
+            }).unwrap();
+            assert_eq!(bins[9..11], [1.0, 1.0], "{:?}", mate_policy); // fragment 900-1050 in both regions
+            assert_eq!(bins[29..31], unsure_bins, "{:?}", mate_policy); // the passing mate alone misses the region before 2940
+            let unsure_fragments = if mate_policy == MatePolicy::DropPair { 0 } else { 1 };
+            assert_eq!(*alignment.filtered_reads(), 1 + unsure_fragments, "{:?}", mate_policy);
+        }
+        let _ = std::fs::remove_file(alignment.file_path());
+        let _ = std::fs::remove_file(alignment.index_path());
+    }
+
+    #[test]
+    fn fragments_reaching_into_a_bed_bin_are_counted() {
+        let mut alignment = indexed_bam("bed-bin-fragment", &[
+            mate("left", 900, 100, 1050), // fragment 900-1150, anchored before the first bin
+            mate("right", 1000, 50, 1160), // fragment 1000-1210, over both bins
+            mate("left", 1050, 100, 900),
+            mate("right", 1160, 50, 1000),
+            mate("outside", 5000, 50, 5100), // in no bin, but part of the library
+            mate("outside", 5100, 50, 5000),
+        ]);
+        let bed_path = std::env::temp_dir().join(format!("bamcowig-{}-bed-bin-fragment.bed", std::process::id()));
+        std::fs::write(&bed_path, "chr1\t1000\t1100\tfirst\nchr1\t1180\t1300\tsecond\n").unwrap();
+        let mut options = CoverageOptions::default();
+        options.set_extend_to_fragment(true).set_bins_bed(Some(Arc::new(BedBins::from_bed(&bed_path).unwrap())));
+        for pair_mates in [false, true] {
+            options.set_pair_mates(pair_mates);
+            let (bins, counted_reads) = coverage(&mut alignment, &options, None);
+            assert_eq!(bins, [2.0, 1.0], "pair mates {}", pair_mates);
+            assert_eq!(counted_reads, 2);
+            alignment.count_filtered_reads(&options, &Filter::default()).unwrap();
+            assert_eq!(*alignment.filtered_reads(), 3, "pair mates {}", pair_mates); // the library is the whole genome
+        }
+        let _ = std::fs::remove_file(bed_path);
+        let _ = std::fs::remove_file(alignment.file_path());
+        let _ = std::fs::remove_file(alignment.index_path());
+    }
+
+    #[test]
+    fn rpgc_of_uniform_coverage_is_one() {
+        let reads: Vec<RecordBuf> = (0..=9900).step_by(50).map(|start| read(&format!("r{}", start), start, 100, false)).collect();
+        let mut alignment = indexed_bam("rpgc", &reads); // depth 2 but for 50 bp at either end
+        let mut options = CoverageOptions::default();
+        options.set_bin_size(500).set_mean_depth(true);
+        let (bins, counted_reads) = coverage(&mut alignment, &options, None);
+        let mut normalized = DenseBins::with_bin_count(bins.len());
+        for (bin, value) in bins.iter().enumerate() {
+            normalized.set(bin, *value);
+        }
+        let normalized = crate::utils::normalizer::rpgc(normalized, counted_reads, 10_000, 100).unwrap();
+        for bin in 0..20 {
+            let expected = if bin == 0 || bin == 19 { 0.95 } else { 1.0 }; // 2 * 10,000 / (199 * 100), less the 50 bp at depth 1 at either end
+            assert!((normalized.get(bin) - expected).abs() < 0.01, "bin {}: {}", bin, normalized.get(bin));
+        }
+        let _ = std::fs::remove_file(alignment.file_path());
+        let _ = std::fs::remove_file(alignment.index_path());
+    }
+
+    #[test]
+    fn fragment_length_is_estimated_from_the_strand_shift() {
+        let mut reads = Vec::new();
+        for copy in 0..3 { // phantom peak at the read length, 9 pairs 49 bp apart
+            reads.push(read(&format!("phantom-forward-{}", copy), 0, 50, false));
+        }
+        for copy in 0..3 {
+            reads.push(read(&format!("phantom-reverse-{}", copy), 0, 50, true));
+        }
+        for (start, fragment_length) in [(1000, 200), (2000, 200), (3000, 200), (5000, 400), (6000, 400), (7000, 400), (8000, 400)] {
+            reads.push(read(&format!("forward-{}", start), start, 50, false));
+            reads.push(read(&format!("reverse-{}", start), start + fragment_length - 50, 50, true));
+        }
+        let alignment = indexed_bam("fragment-estimate", &reads);
+        // the first 12 reads: shifts below read length + 10 are skipped, so the 3 fragments of 200 bp win over the phantom peak
+        assert_eq!(alignment.estimate_fragment_length(&Filter::default(), 12, 600).unwrap(), 200);
+        // all reads: the 4 fragments of 400 bp win
+        assert_eq!(alignment.estimate_fragment_length(&Filter::default(), 1000, 600).unwrap(), 400);
+        let error = alignment.estimate_fragment_length(&Filter::default(), 12, 60).unwrap_err();
+        assert!(error.to_string().contains("as long as the largest fragment"), "{}", error);
+        let _ = std::fs::remove_file(alignment.file_path());
+        let _ = std::fs::remove_file(alignment.index_path());
+    }
+
+    #[test]
+    fn atac_shift_moves_the_cut_sites() {
+        let mut single_end = indexed_bam("atac-single-end", &[read("forward", 100, 50, false), read("reverse", 300, 50, true)]);
+        let mut paired = indexed_bam("atac-paired", &[mate("pair", 100, 50, 250), mate("pair", 250, 50, 100)]);
+        let mut options = CoverageOptions::default();
+        options.set_bin_size(1).set_atac_shift(Some(AtacShift::CutSites));
+        // +4 on the forward 5' end, -5 on the reverse 5' end (the last base, end - 1)
+        assert_eq!(counted_positions(&coverage(&mut single_end, &options, None).0), [104, 344]);
+        assert_eq!(counted_positions(&coverage(&mut paired, &options, None).0), [104, 294]);
+        options.set_atac_shift(Some(AtacShift::Fragments));
+        let shifted_reads: Vec<usize> = (104..154).chain(295..345).collect();
+        assert_eq!(counted_positions(&coverage(&mut single_end, &options, None).0), shifted_reads);
+        // the fragment 100-300 between both shifted ends, counted once
+        assert_eq!(counted_positions(&coverage(&mut paired, &options, None).0), (104..295).collect::<Vec<_>>());
+        for alignment in [single_end, paired] {
+            let _ = std::fs::remove_file(alignment.file_path());
+            let _ = std::fs::remove_file(alignment.index_path());
+        }
+    }
+
+    #[test]
+    fn read_positions_follow_the_strand() {
+        let mut single_end = indexed_bam("positions-single-end", &[read("forward", 100, 50, false), read("reverse", 300, 50, true)]);
+        let mut options = CoverageOptions::default();
+        options.set_bin_size(1);
+        for (count_mode, flip_strand, expected) in [
+            (CountMode::FivePrime, false, [100, 349]),
+            (CountMode::FivePrime, true, [149, 300]),
+            (CountMode::ThreePrime, false, [149, 300]),
+            (CountMode::ThreePrime, true, [100, 349]),
+            (CountMode::Midpoint, false, [125, 325]), // no TLEN, the middle of the read
+        ] {
+            options.set_count_mode(count_mode.clone()).set_flip_strand(flip_strand);
+            let (bins, counted_reads) = coverage(&mut single_end, &options, None);
+            assert_eq!(counted_positions(&bins), expected, "{:?} flip {}", count_mode, flip_strand);
+            assert_eq!(counted_reads, 2);
+        }
+        let mut paired = indexed_bam("positions-paired", &[mate("pair", 100, 50, 250), mate("pair", 250, 50, 100)]);
+        options.set_count_mode(CountMode::Midpoint).set_flip_strand(false);
+        assert_eq!(counted_positions(&coverage(&mut paired, &options, None).0), [200]); // the fragment 100-300, counted once
+        for alignment in [single_end, paired] {
+            let _ = std::fs::remove_file(alignment.file_path());
+            let _ = std::fs::remove_file(alignment.index_path());
+        }
+    }
+
+    #[test]
+    fn alignment_format_comes_from_the_magic() {
+        let alignment = indexed_bam("format", &[mate("pair", 100, 50, 200)]);
+        assert_eq!(detect_alignment_format(alignment.file_path()).unwrap(), AlignmentFormat::Bam);
+
+        let path = |name: &str| std::env::temp_dir().join(format!("bamcowig-{}-{}", std::process::id(), name));
+        std::fs::write(path("format.cram"), b"CRAM\x03\x01").unwrap();
+        assert_eq!(detect_alignment_format(&path("format.cram")).unwrap(), AlignmentFormat::Cram);
+        // a gzip compressed BED named like a BAM
+        let mut writer = noodles_bgzf::io::Writer::new(std::fs::File::create(path("format-bed.bam")).unwrap());
+        std::io::Write::write_all(&mut writer, b"chr1\t0\t100\n").unwrap();
+        writer.finish().unwrap();
+        assert!(detect_alignment_format(&path("format-bed.bam")).unwrap_err().to_string().contains("not BAM"));
+        std::fs::write(path("format.sam"), b"@HD\tVN:1.6\n").unwrap();
+        assert!(detect_alignment_format(&path("format.sam")).is_err());
+        std::fs::write(path("format-empty.bam"), b"").unwrap();
+        assert!(detect_alignment_format(&path("format-empty.bam")).is_err());
+
+        for name in ["format.cram", "format-bed.bam", "format.sam", "format-empty.bam"] {
+            let _ = std::fs::remove_file(path(name));
+        }
+        let _ = std::fs::remove_file(alignment.file_path());
+        let _ = std::fs::remove_file(alignment.index_path());
+    }
+
+    /// Bins of chr1 and the number of counted reads, with the chromosome cut into chunks of `chunk_length`.
+    fn chunked_coverage(alignment: &Alignment<CountableIndex>, options: &CoverageOptions, chunk_length: usize, regions: Option<&IntervalSet>) -> (Vec<f64>, u64) {
+        let mut reader = CountableIndex::chunk_reader(&alignment.index, alignment.file_path(), &fasta::Repository::default(), 1).unwrap();
+        let mut bins = DenseBins::with_bin_count(10000 / *options.bin_size() + 1);
+        let mut counted_reads = 0;
+        for chunk in chromosome_chunks("chr1", 10000, *options.bin_size(), Reach::default(), chunk_length, regions).unwrap() {
+            let (mut tracks, counted) = Alignment::<CountableIndex>::chunk_coverage(&mut reader, &alignment.header, &chunk, &Filter::default(), options, true).unwrap();
+            tracks.swap_remove(0).add_to(&mut bins);
+            counted_reads += counted;
+        }
+        ((0..bins.bin_count()).map(|bin| bins.get(bin)).collect(), counted_reads)
+    }
+
+    #[test]
+    fn reads_straddling_a_chunk_boundary_are_counted_once() {
+        let alignment = indexed_bam("chunk-boundary", &[
+            mate("apart", 4950, 100, 5200), // runs over the boundary at 5000, its mate lies past it
+            mate("overlapping", 4960, 100, 5000), // overlaps its mate, which starts on the boundary
+            mate("overlapping", 5000, 100, 4960),
+            mate("apart", 5200, 100, 4950),
+        ]);
+        let mut regions = IntervalSet::default();
+        regions.insert("chr1".to_string(), 4900..5150);
+        for count_mate_overlap_once in [false, true] {
+            let mut options = CoverageOptions::default();
+            options.set_bin_size(50).set_count_mate_overlap_once(count_mate_overlap_once);
+            for regions in [None, Some(&regions)] {
+                let whole = chunked_coverage(&alignment, &options, 20000, regions);
+                assert_eq!(chunked_coverage(&alignment, &options, 1000, regions), whole);
+                assert_eq!(chunked_coverage(&alignment, &options, 50, regions), whole); // every bin its own chunk
+            }
+            let (bins, counted_reads) = chunked_coverage(&alignment, &options, 1000, None);
+            assert_eq!(counted_reads, 4);
+            assert_eq!(bins[99..102], if count_mate_overlap_once { [2.0, 2.0, 1.0] } else { [2.0, 3.0, 2.0] });
+        }
+        let _ = std::fs::remove_file(alignment.file_path());
+        let _ = std::fs::remove_file(alignment.index_path());
+    }
+
+    #[test]
+    fn chunks_tile_their_spans() {
+        let mut regions = IntervalSet::default();
+        for interval in [120..180, 190..260, 700..2120, 2150..2151, 9990..10000] {
+            regions.insert("chr1".to_string(), interval);
+        }
+        let spans = chromosome_spans("chr1", 10000, 100, Reach::default(), Some(&regions)).unwrap();
+        // intervals sharing a bin are one query, so no bin is filled from two
+        let queries: Vec<_> = spans.iter().map(|span| (span_start(span)..span_end(span), span.bins.clone(), span.counted_from)).collect();
+        assert_eq!(queries, [(120..260, 1..3, 0), (700..2151, 7..22, 260), (9990..10000, 99..100, 2151)]);
+
+        for regions in [None, Some(&regions)] {
+            let spans = chromosome_spans("chr1", 10000, 100, Reach::default(), regions).unwrap();
+            let chunks = chromosome_chunks("chr1", 10000, 100, Reach::default(), 500, regions).unwrap();
+            let mut chunks = chunks.iter().peekable();
+            for span in &spans {
+                let mut start = span_start(span);
+                while start < span_end(span) {
+                    let chunk = chunks.next().unwrap();
+                    assert_eq!(span_start(chunk), start); // no gap or overlap with the previous chunk
+                    assert!(span_end(chunk).is_multiple_of(500) || span_end(chunk) == span_end(span));
+                    assert_eq!(chunk.skip_before, if start == span_start(span) { 0 } else { start });
+                    assert_eq!((&chunk.bins, chunk.counted_from, chunk.query_end), (&span.bins, span.counted_from, span.query_end));
+                    start = span_end(chunk);
+                }
+                assert_eq!(start, span_end(span));
+            }
+            assert!(chunks.peek().is_none());
+        }
+    }
+
+    #[test]
+    fn chunk_length_is_clamped_to_whole_bins() {
+        assert_eq!(chunk_length(1000, 50), MIN_CHUNK_LENGTH.div_ceil(50) * 50);
+        assert_eq!(chunk_length(usize::MAX / 2, 64), MAX_CHUNK_LENGTH);
+        assert_eq!(chunk_length(usize::MAX / 2, 3_000_000), 6_000_000); // rounded up past the limit to whole bins
+        assert_eq!(chunk_length(usize::MAX / 2, 5_000_000), 5_000_000); // a bin longer than the limit stays whole
+        let chunk_length = chunk_length(3_000_000_000, 70);
+        assert!((MIN_CHUNK_LENGTH..MAX_CHUNK_LENGTH + 70).contains(&chunk_length));
+        assert_eq!(chunk_length % 70, 0);
+    }
+
+    #[test]
+    fn batches_cover_every_chunk_in_order() {
+        let chunks: Vec<(usize, QuerySpan)> = [0..300, 300..400, 0..50, 0..20, 20..900, 0..10]
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| (index, chunk_span(chunk, 0..1)))
+            .collect();
+        assert_eq!(batch_chunks(&chunks, 400), [0..2, 2..5, 5..6]);
+        assert_eq!(batch_chunks(&chunks, 1), (0..6).map(|index| index..index + 1).collect::<Vec<_>>());
+        assert_eq!(batch_chunks(&chunks, usize::MAX), [0..6]);
+        assert!(batch_chunks(&[], 400).is_empty());
+    }
+}
@@ -0,0 +1,445 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Range;
+
+/// Bins of one chromosome. Bins that were never added to hold zero.
+pub trait CoverageBins: Send + Sized{
+    fn with_bin_count(bin_count: usize) -> Self;
+    fn bin_count(&self) -> usize;
+    fn get(&self, bin: usize) -> f64;
+    fn set(&mut self, bin: usize, value: f64);
+    fn add(&mut self, bin: usize, value: f64);
+    /// Sets `bins` back to zero, used for masking.
+    fn clear_range(&mut self, bins: Range<usize>);
+    /// Applies `f` to every bin value. `f(0.0)` has to stay 0, zero bins may not be visited.
+    fn map_values<F: Fn(f64) -> f64>(&mut self, f: F);
+    fn sum(&self) -> f64;
+    /// Non-zero bins in order as runs of equal value. Adjacent runs may still share a value.
+    fn into_runs(self) -> Box<dyn Iterator<Item = (Range<usize>, f64)> + Send>;
+
+    /// Adds `values` to the bins starting at `first_bin`.
+    fn add_slice(&mut self, first_bin: usize, values: &[f64]){
+        for (index, value) in values.iter().enumerate(){
+            if *value != 0.0{
+                self.add(first_bin + index, *value);
+            }
+        }
+    }
+}
+
+/// One f64 per bin. Best when most bins are covered.
+pub struct DenseBins(Vec<f64>);
+
+impl CoverageBins for DenseBins{
+    fn with_bin_count(bin_count: usize) -> Self{
+        DenseBins(vec![0.0; bin_count])
+    }
+    fn bin_count(&self) -> usize{
+        self.0.len()
+    }
+    fn get(&self, bin: usize) -> f64{
+        self.0[bin]
+    }
+    fn set(&mut self, bin: usize, value: f64){
+        self.0[bin] = value;
+    }
+    fn add(&mut self, bin: usize, value: f64){
+        self.0[bin] += value;
+    }
+    fn clear_range(&mut self, bins: Range<usize>){
+        self.0[bins].iter_mut().for_each(|bin| *bin = 0.0);
+    }
+    fn map_values<F: Fn(f64) -> f64>(&mut self, f: F){
+        self.0.iter_mut().for_each(|bin| *bin = f(*bin));
+    }
+    fn sum(&self) -> f64{
+        self.0.iter().sum()
+    }
+    fn into_runs(self) -> Box<dyn Iterator<Item = (Range<usize>, f64)> + Send>{
+        Box::new(self.0.into_iter()
+            .enumerate()
+            .filter(|(_, value)| *value != 0.0)
+            .map(|(bin, value)| (bin..bin + 1, value)))
+    }
+    fn add_slice(&mut self, first_bin: usize, values: &[f64]){
+        for (bin, value) in self.0[first_bin..first_bin + values.len()].iter_mut().zip(values){
+            *bin += value;
+        }
+    }
+}
+
+/// Only the non-zero bins, in a sorted map. Best for scattered counts such as 5' ends at 1 bp.
+pub struct SparseBins{
+    bin_count: usize,
+    values: BTreeMap<usize, f64>,
+}
+
+impl CoverageBins for SparseBins{
+    fn with_bin_count(bin_count: usize) -> Self{
+        SparseBins{ bin_count, values: BTreeMap::new() }
+    }
+    fn bin_count(&self) -> usize{
+        self.bin_count
+    }
+    fn get(&self, bin: usize) -> f64{
+        self.values.get(&bin).copied().unwrap_or(0.0)
+    }
+    fn set(&mut self, bin: usize, value: f64){
+        if value == 0.0{
+            self.values.remove(&bin);
+        }else{
+            self.values.insert(bin, value);
+        }
+    }
+    fn add(&mut self, bin: usize, value: f64){
+        *self.values.entry(bin).or_insert(0.0) += value;
+    }
+    fn clear_range(&mut self, bins: Range<usize>){
+        let mut tail = self.values.split_off(&bins.start);
+        let mut after = tail.split_off(&bins.end);
+        self.values.append(&mut after);
+    }
+    fn map_values<F: Fn(f64) -> f64>(&mut self, f: F){
+        self.values.values_mut().for_each(|value| *value = f(*value));
+    }
+    fn sum(&self) -> f64{
+        self.values.values().sum()
+    }
+    fn into_runs(self) -> Box<dyn Iterator<Item = (Range<usize>, f64)> + Send>{
+        Box::new(self.values.into_iter()
+            .filter(|(_, value)| *value != 0.0)
+            .map(|(bin, value)| (bin..bin + 1, value)))
+    }
+}
+
+/// Runs of equal non-zero value, sorted and disjoint. Best for coverage at bin sizes well below the read length,
+/// where neighbouring bins mostly hold the same depth. Meant to be filled front to back with `add_slice`, which then only
+/// rebuilds the few runs reaching into the slice. `add` and `set` in the middle cost O(runs after the bin).
+pub struct RunLengthBins{
+    bin_count: usize,
+    runs: Vec<(Range<usize>, f64)>,
+}
+
+impl RunLengthBins{
+    /// Index of the run holding `bin`, or where a run starting at `bin` would be inserted.
+    fn find(&self, bin: usize) -> Result<usize, usize>{
+        let index = self.runs.partition_point(|(run, _)| run.end <= bin);
+        match self.runs.get(index){
+            Some((run, _)) if run.start <= bin => Ok(index),
+            _ => Err(index),
+        }
+    }
+
+    /// Appends a run, joining the last one when they touch and hold the same value.
+    fn push_run(&mut self, bins: Range<usize>, value: f64){
+        if value == 0.0 || bins.is_empty(){
+            return;
+        }
+        match self.runs.last_mut(){
+            Some((run, last_value)) if run.end == bins.start && *last_value == value => run.end = bins.end,
+            _ => self.runs.push((bins, value)),
+        }
+    }
+
+    /// Joins the run at `index` with its neighbours when they touch and hold the same value.
+    fn merge_around(&mut self, index: usize){
+        if index + 1 < self.runs.len() && self.runs[index].0.end == self.runs[index + 1].0.start && self.runs[index].1 == self.runs[index + 1].1{
+            let next = self.runs.remove(index + 1);
+            self.runs[index].0.end = next.0.end;
+        }
+        if index > 0 && self.runs[index - 1].0.end == self.runs[index].0.start && self.runs[index - 1].1 == self.runs[index].1{
+            let run = self.runs.remove(index);
+            self.runs[index - 1].0.end = run.0.end;
+        }
+    }
+}
+
+impl CoverageBins for RunLengthBins{
+    fn with_bin_count(bin_count: usize) -> Self{
+        RunLengthBins{ bin_count, runs: Vec::new() }
+    }
+    fn bin_count(&self) -> usize{
+        self.bin_count
+    }
+    fn get(&self, bin: usize) -> f64{
+        self.find(bin).map(|index| self.runs[index].1).unwrap_or(0.0)
+    }
+    fn set(&mut self, bin: usize, value: f64){
+        self.clear_range(bin..bin + 1);
+        if value != 0.0{
+            let index = self.find(bin).unwrap_err();
+            self.runs.insert(index, (bin..bin + 1, value));
+            self.merge_around(index);
+        }
+    }
+    fn add(&mut self, bin: usize, value: f64){
+        self.add_slice(bin, &[value]);
+    }
+    fn clear_range(&mut self, bins: Range<usize>){
+        if bins.is_empty(){
+            return;
+        }
+        let first = self.runs.partition_point(|(run, _)| run.end <= bins.start);
+        let last = self.runs.partition_point(|(run, _)| run.start < bins.end);
+        if first >= last{
+            return;
+        }
+        let mut kept = Vec::new();
+        let (first_run, first_value) = self.runs[first].clone();
+        if first_run.start < bins.start{
+            kept.push((first_run.start..bins.start, first_value));
+        }
+        let (last_run, last_value) = self.runs[last - 1].clone();
+        if last_run.end > bins.end{
+            kept.push((bins.end..last_run.end, last_value));
+        }
+        self.runs.splice(first..last, kept);
+    }
+    fn map_values<F: Fn(f64) -> f64>(&mut self, f: F){
+        self.runs.iter_mut().for_each(|(_, value)| *value = f(*value));
+    }
+    fn sum(&self) -> f64{
+        self.runs.iter().map(|(run, value)| value * run.len() as f64).sum()
+    }
+    fn into_runs(self) -> Box<dyn Iterator<Item = (Range<usize>, f64)> + Send>{
+        Box::new(self.runs.into_iter().filter(|(_, value)| *value != 0.0))
+    }
+    fn add_slice(&mut self, first_bin: usize, values: &[f64]){
+        // runs ending after `first_bin` are rebuilt with the slice added, everything before it stays
+        let tail = self.runs.split_off(self.runs.partition_point(|(run, _)| run.end <= first_bin));
+        let mut added: Vec<(Range<usize>, f64)> = Vec::new();
+        for (index, value) in values.iter().enumerate(){
+            let bin = first_bin + index;
+            match added.last_mut(){
+                Some((run, last_value)) if run.end == bin && *last_value == *value => run.end += 1,
+                _ if *value == 0.0 => {}
+                _ => added.push((bin..bin + 1, *value)),
+            }
+        }
+        if tail.is_empty(){
+            added.into_iter().for_each(|(bins, value)| self.push_run(bins, value));
+            return;
+        }
+        let mut boundaries: Vec<usize> = tail.iter().chain(&added).flat_map(|(run, _)| [run.start, run.end]).collect();
+        boundaries.sort_unstable();
+        boundaries.dedup();
+        let (mut tail_index, mut added_index) = (0, 0);
+        for segment in boundaries.windows(2){
+            let start = segment[0];
+            while tail.get(tail_index).is_some_and(|(run, _)| run.end <= start){
+                tail_index += 1;
+            }
+            while added.get(added_index).is_some_and(|(run, _)| run.end <= start){
+                added_index += 1;
+            }
+            let value_at = |runs: &[(Range<usize>, f64)], index: usize| runs.get(index).filter(|(run, _)| run.start <= start).map_or(0.0, |(_, value)| *value);
+            self.push_run(start..segment[1], value_at(&tail, tail_index) + value_at(&added, added_index));
+        }
+    }
+}
+
+/// Sliding windows over step-sized bins: step `i` gets the sum of the `steps_per_window` steps centred on it
+/// (one more after than before for even counts). With `average` the sum is divided by the step count,
+/// which keeps mean depth and fractions relative to the window width.
+pub fn sum_windows<C: CoverageBins>(steps: C, steps_per_window: usize, average: bool) -> C{
+    let step_count = steps.bin_count();
+    let before = (steps_per_window - 1) / 2; // window of step i is i - before .. i + after
+    let after = steps_per_window - before;
+    let mut windows = C::with_bin_count(step_count);
+    let mut steps = steps.into_runs()
+        .flat_map(|(bins, value)| bins.map(move |bin| (bin, value)))
+        .peekable();
+    let mut in_window: VecDeque<(usize, f64)> = VecDeque::new();
+    let mut sum = 0.0;
+    let mut center = 0usize;
+    while center < step_count{
+        if in_window.is_empty(){ // jump to the first window reaching the next non-zero step
+            let Some(&(bin, _)) = steps.peek() else { break };
+            center = center.max((bin + 1).saturating_sub(after));
+            sum = 0.0; // no leftover rounding from steps that left the window
+            if center >= step_count{
+                break;
+            }
+        }
+        while let Some(&(bin, value)) = steps.peek() && bin < center + after{
+            in_window.push_back((bin, value));
+            sum += value;
+            steps.next();
+        }
+        while let Some(&(bin, value)) = in_window.front() && bin + before < center{
+            in_window.pop_front();
+            sum -= value;
+        }
+        if !in_window.is_empty(){
+            windows.set(center, if average { sum / steps_per_window as f64 } else { sum });
+        }
+        center += 1;
+    }
+    windows
+}
+
+/// Which container holds the bins of a chromosome.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(clap::ValueEnum)]
+pub enum BinStorage {
+    Auto,
+    Dense,
+    Sparse,
+    RunLength,
+}
+
+impl BinStorage {
+    /// Picks a container from the expected share of non-empty bins (Poisson, reads spread evenly over the genome)
+    /// and from how many bins one read spans. `Auto` only, other choices are returned as they are.
+    pub fn resolve(&self, read_count: u64, read_length: usize, genome_length: usize, bin_size: usize, counts_single_positions: bool) -> BinStorage{
+        if *self != BinStorage::Auto{
+            return self.clone();
+        }
+        let bins_per_read = if counts_single_positions { 1 } else { read_length / bin_size + 1 };
+        let covered_share = 1.0 - (-(read_count as f64) * (bins_per_read * bin_size) as f64 / genome_length.max(1) as f64).exp();
+        if covered_share >= 0.25{
+            BinStorage::Dense
+        }else if bins_per_read >= 4{ // reads are long in bins, their depth comes in runs
+            BinStorage::RunLength
+        }else{
+            BinStorage::Sparse
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runs<C: CoverageBins>(bins: C) -> Vec<(Range<usize>, f64)> {
+        bins.into_runs().collect()
+    }
+
+    /// Values of all bins, zero ones included.
+    fn values<C: CoverageBins>(bins: &C) -> Vec<f64> {
+        (0..bins.bin_count()).map(|bin| bins.get(bin)).collect()
+    }
+
+    /// What every container has to agree on.
+    fn check_container<C: CoverageBins>() {
+        let mut bins = C::with_bin_count(8);
+        assert_eq!(bins.bin_count(), 8);
+        assert_eq!(values(&bins), [0.0; 8]);
+        bins.add(2, 1.0);
+        bins.add(2, 2.0);
+        bins.add(5, 1.5);
+        bins.set(6, 4.0);
+        assert_eq!(values(&bins), [0.0, 0.0, 3.0, 0.0, 0.0, 1.5, 4.0, 0.0]);
+        bins.set(6, 0.0);
+        assert_eq!(bins.get(6), 0.0);
+        assert_eq!(bins.sum(), 4.5);
+        bins.map_values(|value| value * 2.0);
+        assert_eq!(values(&bins), [0.0, 0.0, 6.0, 0.0, 0.0, 3.0, 0.0, 0.0]);
+        bins.add_slice(4, &[1.0, 1.0, 0.0]);
+        bins.clear_range(2..3);
+        assert_eq!(values(&bins), [0.0, 0.0, 0.0, 0.0, 1.0, 4.0, 0.0, 0.0]);
+        let non_zero: Vec<(usize, f64)> = runs(bins).into_iter().flat_map(|(bins, value)| bins.map(move |bin| (bin, value))).collect();
+        assert_eq!(non_zero, [(4, 1.0), (5, 4.0)]);
+    }
+
+    #[test]
+    fn dense_bins() {
+        check_container::<DenseBins>();
+    }
+
+    #[test]
+    fn sparse_bins() {
+        check_container::<SparseBins>();
+    }
+
+    #[test]
+    fn run_length_bins() {
+        check_container::<RunLengthBins>();
+    }
+
+    #[test]
+    fn run_length_bins_join_equal_neighbours() {
+        let mut bins = RunLengthBins::with_bin_count(10);
+        bins.add_slice(0, &[1.0, 1.0, 2.0]);
+        bins.add_slice(3, &[2.0, 2.0, 0.0, 1.0]);
+        assert_eq!(bins.runs, [(0..2, 1.0), (2..5, 2.0), (6..7, 1.0)]);
+        bins.set(5, 2.0); // fills the gap between two runs of the same value
+        assert_eq!(bins.runs, [(0..2, 1.0), (2..6, 2.0), (6..7, 1.0)]);
+        bins.set(3, 0.0);
+        assert_eq!(runs(bins), [(0..2, 1.0), (2..3, 2.0), (4..6, 2.0), (6..7, 1.0)]);
+    }
+
+    #[test]
+    fn run_length_bins_merge_a_slice_reaching_back() {
+        let mut bins = RunLengthBins::with_bin_count(12);
+        bins.add_slice(0, &[1.0, 1.0, 1.0, 1.0, 2.0, 2.0]);
+        bins.add_slice(3, &[1.0, 1.0, 0.0, 3.0, 3.0]); // the next chunk overlaps the last runs
+        assert_eq!(bins.runs, [(0..3, 1.0), (3..4, 2.0), (4..5, 3.0), (5..6, 2.0), (6..8, 3.0)]);
+        assert_eq!(values(&bins), [1.0, 1.0, 1.0, 2.0, 3.0, 2.0, 3.0, 3.0, 0.0, 0.0, 0.0, 0.0]);
+        bins.add(0, -1.0); // a run dropping to zero goes away
+        assert_eq!(bins.runs[0], (1..3, 1.0));
+    }
+
+    /// Window sums the slow way: step `i` sums steps `i - before .. i + after`, cut at both chromosome ends.
+    fn windows_by_hand(steps: &[f64], steps_per_window: usize, average: bool) -> Vec<f64> {
+        let before = (steps_per_window - 1) / 2;
+        let after = steps_per_window - before;
+        (0..steps.len())
+            .map(|center| {
+                let sum: f64 = steps[center.saturating_sub(before)..(center + after).min(steps.len())].iter().sum();
+                if average { sum / steps_per_window as f64 } else { sum }
+            })
+            .collect()
+    }
+
+    fn windows<C: CoverageBins>(steps: &[f64], steps_per_window: usize, average: bool) -> Vec<f64> {
+        let mut bins = C::with_bin_count(steps.len());
+        bins.add_slice(0, steps);
+        values(&sum_windows(bins, steps_per_window, average))
+    }
+
+    #[test]
+    fn windows_are_centred_on_their_step() {
+        let steps = [0.0, 1.0, 0.0, 0.0, 2.0, 0.0, 0.0, 0.0, 0.0, 4.0, 0.0, 0.0];
+        // three steps: one on either side
+        assert_eq!(windows::<DenseBins>(&steps, 3, false), [1.0, 1.0, 1.0, 2.0, 2.0, 2.0, 0.0, 0.0, 4.0, 4.0, 4.0, 0.0]);
+        // four steps: one before, two after
+        assert_eq!(windows::<DenseBins>(&steps, 4, false)[..6], [1.0, 1.0, 3.0, 2.0, 2.0, 2.0]);
+        for steps_per_window in 1..=6 {
+            for average in [false, true] {
+                let expected = windows_by_hand(&steps, steps_per_window, average);
+                assert_eq!(windows::<DenseBins>(&steps, steps_per_window, average), expected);
+                assert_eq!(windows::<SparseBins>(&steps, steps_per_window, average), expected);
+                assert_eq!(windows::<RunLengthBins>(&steps, steps_per_window, average), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn last_window_is_cut_at_the_chromosome_end() {
+        let steps = [0.0, 0.0, 0.0, 3.0, 6.0];
+        let sums = windows::<DenseBins>(&steps, 5, false);
+        assert_eq!(sums, [0.0, 3.0, 9.0, 9.0, 9.0]); // the last steps only see what is left of the chromosome
+        let averages = windows::<DenseBins>(&steps, 5, true);
+        assert_eq!(averages[4], 9.0 / 5.0); // still averaged over the full window width
+    }
+
+    #[test]
+    fn resolve_keeps_explicit_choices() {
+        for storage in [BinStorage::Dense, BinStorage::Sparse, BinStorage::RunLength] {
+            assert_eq!(storage.resolve(1_000_000_000, 100, 1000, 1, false), storage);
+        }
+    }
+
+    #[test]
+    fn resolve_picks_from_read_density() {
+        let genome_length = 3_000_000_000;
+        // deep coverage fills most bins
+        assert_eq!(BinStorage::Auto.resolve(50_000_000, 100, genome_length, 50, false), BinStorage::Dense);
+        // a few reads at 1 bp bins: long runs of the same depth
+        assert_eq!(BinStorage::Auto.resolve(100_000, 100, genome_length, 1, false), BinStorage::RunLength);
+        // a few reads counted at one position each
+        assert_eq!(BinStorage::Auto.resolve(100_000, 100, genome_length, 1, true), BinStorage::Sparse);
+        // reads shorter than four bins
+        assert_eq!(BinStorage::Auto.resolve(100_000, 100, genome_length, 50, false), BinStorage::Sparse);
+    }
+}
@@ -0,0 +1,135 @@
+use std::sync::Arc;
+use getset::{Getters, Setters, MutGetters};
+use crate::utils::intervals::BedBins;
+
+/// What ATAC-seq reads add after the Tn5 shift (+4 on forward, -5 on reverse strand 5' ends).
+#[derive(Clone, Debug, PartialEq, clap::ValueEnum)]
+pub enum AtacShift {
+    /// One base at each shifted 5' end
+    CutSites,
+    /// The shifted fragment (paired-end) or shifted read (single-end)
+    Fragments,
+}
+
+/// Which single position of a read or fragment is counted. `Coverage` counts the whole span.
+#[derive(Clone, Debug, PartialEq, clap::ValueEnum)]
+pub enum CountMode {
+    Coverage,
+    /// Read 5' end (CAGE, ChIP-exo)
+    FivePrime,
+    /// Read 3' end (PRO-seq with --flip-strand)
+    ThreePrime,
+    /// Fragment center (MNase)
+    Midpoint,
+}
+
+/// Library strandedness, used to assign reads to the transcript strand when tracks are split.
+#[derive(Clone, Debug, PartialEq, clap::ValueEnum)]
+pub enum LibraryType {
+    /// Read strand as aligned
+    Unstranded,
+    /// First mate (or single read) on the transcript strand, e.g. ligation or fr-secondstrand
+    Forward,
+    /// First mate (or single read) opposite to the transcript, e.g. dUTP or fr-firststrand
+    #[value(alias = "dutp")]
+    Reverse,
+}
+
+impl LibraryType {
+    /// True if the transcript the read came from is on the reverse strand.
+    pub fn transcript_is_reverse(&self, flags: noodles_sam::alignment::record::Flags) -> bool{
+        let is_reverse = flags.is_reverse_complemented();
+        let is_second_mate = flags.is_segmented() && flags.is_last_segment();
+        match self{
+            LibraryType::Unstranded => is_reverse,
+            LibraryType::Forward => is_reverse != is_second_mate,
+            LibraryType::Reverse => is_reverse == is_second_mate,
+        }
+    }
+}
+
+/// How reads are turned into bin values. Filter decides which reads, this decides what they add.
+#[derive(Clone, Debug)]
+#[derive(Getters, Setters, MutGetters)]
+#[getset(get = "pub", set = "pub")]
+pub struct CoverageOptions{
+    bin_size: usize,
+    window_size: Option<usize>, // sliding windows of this width, advancing by bin_size (the step). None for plain bins
+    bins_bed: Option<Arc<BedBins>>, // variable-width bins replacing the bin_size tiles
+    extend_to_fragment: bool,
+    pair_mates: bool,               // paired-end fragments from both mates found by name, instead of TLEN
+    fragment_length: Option<usize>, // single-end extension length, TLEN is used when unset
+    atac_shift: Option<AtacShift>,
+    count_mode: CountMode,
+    flip_strand: bool,        // swap the read strand before picking the 5'/3' end
+    split_strands: bool,      // forward and reverse transcript strand go to separate tracks
+    library_type: LibraryType,
+    fraction_counts: bool,
+    mean_depth: bool,         // bins hold mean per-base depth instead of read counts
+    count_deletions: bool,    // CIGAR D counts as covered. N (introns) never does
+    include_soft_clips: bool, // soft clipped bases extend the read at either end
+    count_mate_overlap_once: bool, // bases shared by overlapping mates of a proper pair count once
+    count_reads_only: bool,   // only the number of counted reads is wanted, no bins are filled
+}
+
+
+impl Default for CoverageOptions{
+    fn default() -> CoverageOptions {
+        CoverageOptions {bin_size: 50,
+            window_size: None,
+            bins_bed: None,
+            extend_to_fragment: false,
+            pair_mates: false,
+            fragment_length: None,
+            atac_shift: None,
+            count_mode: CountMode::Coverage,
+            flip_strand: false,
+            split_strands: false,
+            library_type: LibraryType::Unstranded,
+            fraction_counts: false,
+            mean_depth: false,
+            count_deletions: true,
+            include_soft_clips: false,
+            count_mate_overlap_once: false,
+            count_reads_only: false,
+        }
+   }
+}
+
+impl CoverageOptions{
+    /// Number of coverage tracks filled in one pass: forward and reverse, or just one.
+    pub fn track_count(&self) -> usize{
+        if self.split_strands { 2 } else { 1 }
+    }
+
+    /// Width one output value stands for: the window, or the bin without windows.
+    pub fn value_width(&self) -> usize{
+        self.window_size.unwrap_or(self.bin_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noodles_sam::alignment::record::Flags;
+
+    #[test]
+    fn transcript_strand_by_library_type() {
+        let first = Flags::SEGMENTED | Flags::FIRST_SEGMENT;
+        let second = Flags::SEGMENTED | Flags::LAST_SEGMENT;
+        let reverse = Flags::REVERSE_COMPLEMENTED;
+        // (read, unstranded, forward, reverse/dUTP)
+        for (flags, expected) in [
+            (Flags::empty(), [false, false, true]), // single-end reads are read like first mates
+            (reverse, [true, true, false]),
+            (first, [false, false, true]),
+            (first | reverse, [true, true, false]),
+            (second, [false, true, false]),
+            (second | reverse, [true, false, true]),
+        ] {
+            for (library_type, expected) in [LibraryType::Unstranded, LibraryType::Forward, LibraryType::Reverse].iter().zip(expected) {
+                assert_eq!(library_type.transcript_is_reverse(flags), expected, "{:?} {:?}", library_type, flags);
+            }
+        }
+    }
+}
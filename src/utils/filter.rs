@@ -1,13 +1,13 @@
-
-
-
-use noodles_util::alignment::Record;
-use noodles_sam::alignment::Record as _;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+use crate::utils::intervals::{IntervalSet, overlaps_any};
+use noodles_sam::alignment::Record;
 use getset::{Getters, Setters, MutGetters};
-use crate::utils::alignment_handler::Alignment;
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 #[derive(Default)]
+#[derive(clap::ValueEnum)]
 pub enum PairFilter {
     Strict,    // Only properly paired
     #[default]
@@ -15,46 +15,118 @@ pub enum PairFilter {
     Off,       // No pair filtering at all
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 #[derive(PartialEq)]
+#[derive(clap::ValueEnum)]
 pub enum StrandSelection {
     Both,
     Forward,
     Reverse,
 }
 
-#[derive(Clone)]
+/// What to do with a pair when only one mate passes the filter, used when mates are paired by name.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(clap::ValueEnum)]
+pub enum MatePolicy {
+    DropPair,  // count neither mate
+    KeepMate,  // count the passing mate on its own
+    KeepPair,  // count the whole fragment anyway
+}
+
+#[derive(Clone, Debug)]
 #[derive(Getters, Setters, MutGetters)]
 #[getset(get = "pub", set = "pub")]
 pub struct Filter{
     minimum_mapping_quality: u32,
+    keep_unavailable_mapping_quality: bool, // MAPQ 255 means "not available". STAR also writes it for unique hits.
     ignore_duplicates_flag: bool,
     strand_selection: StrandSelection,
     secondary_alignment_skip: bool,
     supplementary_alignment_skip: bool,
     pair_filter: PairFilter,
+    mate_policy: MatePolicy,
+    minimum_fragment_length: Option<usize>, // on |TLEN| of paired records, single-end reads are not affected
+    maximum_fragment_length: Option<usize>,
+    blacklist: Option<Arc<IntervalSet>>, // reads overlapping these are dropped, see `on_chromosome`. apply() has no chromosome to look them up.
 }
 
 
 impl Default for Filter{
     fn default() -> Filter {
         Filter {minimum_mapping_quality: 10, 
+            keep_unavailable_mapping_quality: true,
             ignore_duplicates_flag:false, 
             strand_selection: StrandSelection::Both,
             secondary_alignment_skip: true,
             supplementary_alignment_skip: true,
             pair_filter: PairFilter::Strict,
+            mate_policy: MatePolicy::DropPair,
+            minimum_fragment_length: None,
+            maximum_fragment_length: None,
+            blacklist: None,
         }
    }
 }
 
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Filter in effect:")?;
+        writeln!(f, "  minimum MAPQ:          {}", self.minimum_mapping_quality)?;
+        writeln!(f, "  MAPQ 255 (unavailable): {}", if self.keep_unavailable_mapping_quality { "kept" } else { "dropped" })?;
+        writeln!(f, "  duplicates:            {}", if self.ignore_duplicates_flag { "kept" } else { "dropped" })?;
+        writeln!(f, "  strand:                {:?}", self.strand_selection)?;
+        writeln!(f, "  secondary alignments:  {}", if self.secondary_alignment_skip { "dropped" } else { "kept" })?;
+        writeln!(f, "  supplementary:         {}", if self.supplementary_alignment_skip { "dropped" } else { "kept" })?;
+        writeln!(f, "  pair filter:           {:?}", self.pair_filter)?;
+        writeln!(f, "  one mate failing:      {:?}", self.mate_policy)?;
+        match (self.minimum_fragment_length, self.maximum_fragment_length) {
+            (None, None) => writeln!(f, "  fragment length:       any")?,
+            (Some(minimum), None) => writeln!(f, "  fragment length:       at least {} bp", minimum)?,
+            (None, Some(maximum)) => writeln!(f, "  fragment length:       at most {} bp", maximum)?,
+            (Some(minimum), Some(maximum)) => writeln!(f, "  fragment length:       {}-{} bp", minimum, maximum)?,
+        }
+        match &self.blacklist {
+            Some(blacklist) => write!(f, "  blacklist:             {} intervals, {} bp", blacklist.interval_count(), blacklist.total_length()),
+            None => write!(f, "  blacklist:             none"),
+        }
+    }
+}
+
 impl Filter{
 
-    pub fn apply(&self, record: &Box<dyn noodles_sam::alignment::Record>) -> Result<bool, Box<dyn std::error::Error>> {
+    /// Checks that the settings make sense together. Call it after building the filter through the setters.
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.minimum_mapping_quality == 255 && !self.keep_unavailable_mapping_quality {
+            return Err("minimum MAPQ of 255 while dropping MAPQ 255 reads would filter out every read".into());
+        }
+        if let (Some(minimum), Some(maximum)) = (self.minimum_fragment_length, self.maximum_fragment_length) && minimum > maximum {
+            return Err(format!("minimum fragment length {} is larger than the maximum {}", minimum, maximum).into());
+        }
+        Ok(())
+    }
+
+    /// The filter for records on `chromosome`, with its blacklisted intervals looked up once.
+    pub fn on_chromosome(&self, chromosome: &str) -> ChromosomeFilter<'_> {
+        let blacklist = match &self.blacklist {
+            Some(blacklist) => blacklist.get(chromosome),
+            None => &[],
+        };
+        ChromosomeFilter { filter: self, blacklist }
+    }
+
+    pub fn apply(&self, record: &dyn Record) -> Result<bool, Box<dyn std::error::Error>> {
+        if self.apply_ignoring_template_length(record)? {
+            return Ok(true);
+        }
+        Ok((self.minimum_fragment_length.is_some() || self.maximum_fragment_length.is_some()) && self.check_fragment_length(record)?)
+    }
+
+    /// `apply` without the fragment length check on TLEN, for fragments rebuilt from both mates (see `accepts_fragment_length`).
+    pub fn apply_ignoring_template_length(&self, record: &dyn Record) -> Result<bool, Box<dyn std::error::Error>> {
         if self.check_alignment(record)?{
             return Ok(true);
         }
-        if self.minimum_mapping_quality > 0 && self.check_mapping_quality(record)? {
+        if (self.minimum_mapping_quality > 0 || !self.keep_unavailable_mapping_quality) && self.check_mapping_quality(record)? {
             return Ok(true);
         }
         if !self.ignore_duplicates_flag && self.check_duplicate(record)? {
@@ -72,22 +144,32 @@ impl Filter{
         Ok(false)
     }
 
-    fn check_mapping_quality(&self, record: &Box<dyn noodles_sam::alignment::Record>) -> Result<bool, Box<dyn std::error::Error>>{
+    /// Whether a fragment of `fragment_length` bp is within the minimum and maximum. `None` is a fragment of unknown length,
+    /// which like a TLEN of 0 only passes when no bound is set.
+    pub fn accepts_fragment_length(&self, fragment_length: Option<usize>) -> bool {
+        match fragment_length {
+            Some(fragment_length) => self.minimum_fragment_length.is_none_or(|minimum| fragment_length >= minimum)
+                && self.maximum_fragment_length.is_none_or(|maximum| fragment_length <= maximum),
+            None => self.minimum_fragment_length.is_none() && self.maximum_fragment_length.is_none(),
+        }
+    }
+
+    fn check_mapping_quality(&self, record: &dyn Record) -> Result<bool, Box<dyn std::error::Error>>{
         let quality = record.mapping_quality()
         .transpose()? //flips Option and Result from mapping_quality
         .map(|mq| mq.get() as u32) // MappingQuality is a wrapper around u8, .get() extracts the u8, then cast to i32
-        .unwrap_or(0); // None means unmap. So 0 in that case to filter.         
+        .unwrap_or(255); // noodles returns None for 255 (missing)
         if quality == 255{
-            Ok(false) // 255 means quality score is not available
+            Ok(!self.keep_unavailable_mapping_quality) // 255 means quality score is not available
         }else{
             Ok(quality < self.minimum_mapping_quality)
         }
     }
-    fn check_duplicate(&self, record: &Box<dyn noodles_sam::alignment::Record>) -> Result<bool, Box<dyn std::error::Error>>{
+    fn check_duplicate(&self, record: &dyn Record) -> Result<bool, Box<dyn std::error::Error>>{
         let duplicate_alignment = record.flags()?.is_duplicate();
         Ok(duplicate_alignment)
     }
-    fn check_strand_selection(&self, record: &Box<dyn noodles_sam::alignment::Record>) -> Result<bool, Box<dyn std::error::Error>>{
+    fn check_strand_selection(&self, record: &dyn Record) -> Result<bool, Box<dyn std::error::Error>>{
         let flags = record.flags()?;
         match self.strand_selection {
             StrandSelection::Both => Ok(false),
@@ -95,15 +177,23 @@ impl Filter{
             StrandSelection::Reverse => Ok(!flags.is_reverse_complemented()), // Thats why it is reversed.
         }
     }
-    fn check_secondary_alignment(&self, record: &Box<dyn noodles_sam::alignment::Record>) -> Result<bool, Box<dyn std::error::Error>>{
+    fn check_secondary_alignment(&self, record: &dyn Record) -> Result<bool, Box<dyn std::error::Error>>{
         let secondary_alignment = record.flags()?.is_secondary();
         Ok(secondary_alignment)
     }
-    fn check_supplementary_alignment(&self, record: &Box<dyn noodles_sam::alignment::Record>) -> Result<bool, Box<dyn std::error::Error>>{
+    fn check_supplementary_alignment(&self, record: &dyn Record) -> Result<bool, Box<dyn std::error::Error>>{
         let supplementary_alignment = record.flags()?.is_supplementary();
         Ok(supplementary_alignment)
     }
-    fn check_alignment(&self, record: &Box<dyn noodles_sam::alignment::Record>) -> Result<bool, Box<dyn std::error::Error>> {
+    fn check_fragment_length(&self, record: &dyn Record) -> Result<bool, Box<dyn std::error::Error>>{
+        if !record.flags()?.is_segmented() {
+            return Ok(false);
+        }
+        let fragment_length = record.template_length()?.unsigned_abs() as usize;
+        // 0 is a mate unmapped or on another chromosome, the length is unknown
+        Ok(!self.accepts_fragment_length(Some(fragment_length).filter(|&fragment_length| fragment_length > 0)))
+    }
+    fn check_alignment(&self, record: &dyn Record) -> Result<bool, Box<dyn std::error::Error>> {
         let flags = record.flags()?;
 
         match self.pair_filter {
@@ -125,4 +215,124 @@ impl Filter{
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// `Filter` for the records of one chromosome, adding the blacklist to `Filter::apply`.
+pub struct ChromosomeFilter<'a> {
+    filter: &'a Filter,
+    blacklist: &'a [Range<usize>],
+}
+
+impl ChromosomeFilter<'_> {
+
+    /// `Filter::apply`, and true as well when the alignment overlaps a blacklisted interval.
+    pub fn apply(&self, record: &dyn Record) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self.filter.apply(record)? || self.is_record_blacklisted(record)?)
+    }
+
+    /// `Filter::apply_ignoring_template_length` with the blacklist.
+    pub fn apply_ignoring_template_length(&self, record: &dyn Record) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self.filter.apply_ignoring_template_length(record)? || self.is_record_blacklisted(record)?)
+    }
+
+    fn is_record_blacklisted(&self, record: &dyn Record) -> Result<bool, Box<dyn std::error::Error>> {
+        if self.blacklist.is_empty() {
+            return Ok(false);
+        }
+        let (Some(start), Some(end)) = (record.alignment_start().transpose()?, record.alignment_end().transpose()?) else { return Ok(false) };
+        Ok(self.is_blacklisted(&((start.get() - 1)..end.get())))
+    }
+
+    /// True if `interval` (0-based, half-open) overlaps the blacklist. For what a read turns into, e.g. its extended fragment.
+    pub fn is_blacklisted(&self, interval: &Range<usize>) -> bool {
+        overlaps_any(self.blacklist, interval)
+    }
+
+    pub fn mate_policy(&self) -> &MatePolicy {
+        self.filter.mate_policy()
+    }
+
+    pub fn accepts_fragment_length(&self, fragment_length: Option<usize>) -> bool {
+        self.filter.accepts_fragment_length(fragment_length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noodles_core::Position;
+    use noodles_sam::alignment::RecordBuf;
+    use noodles_sam::alignment::record::cigar::{Op, op::Kind};
+    use noodles_sam::alignment::record::{Flags, MappingQuality};
+
+    /// Mapped single-end read at 0-based `start`, `length` bp.
+    fn read(start: usize, length: usize) -> RecordBuf {
+        RecordBuf::builder()
+            .set_flags(Flags::empty())
+            .set_reference_sequence_id(0)
+            .set_alignment_start(Position::try_from(start + 1).unwrap())
+            .set_mapping_quality(MappingQuality::new(60).unwrap())
+            .set_cigar([Op::new(Kind::Match, length)].into_iter().collect())
+            .build()
+    }
+
+    fn blacklisted_filter(interval: Range<usize>) -> Filter {
+        let mut blacklist = IntervalSet::default();
+        blacklist.insert("chr1".to_string(), interval);
+        let mut filter = Filter::default();
+        filter.set_blacklist(Some(Arc::new(blacklist)));
+        filter
+    }
+
+    #[test]
+    fn reads_overlapping_the_blacklist_are_dropped() {
+        let filter = blacklisted_filter(100..200);
+        let chr1 = filter.on_chromosome("chr1");
+        assert!(chr1.apply(&read(150, 10)).unwrap());
+        assert!(chr1.apply(&read(50, 51)).unwrap()); // last base at 100
+        assert!(!chr1.apply(&read(50, 50)).unwrap()); // ends right before it
+        assert!(!chr1.apply(&read(200, 50)).unwrap());
+        assert!(!filter.on_chromosome("chr2").apply(&read(150, 10)).unwrap());
+    }
+
+    #[test]
+    fn extended_fragments_are_checked_on_their_own() {
+        let filter = blacklisted_filter(300..400);
+        let chr1 = filter.on_chromosome("chr1");
+        let record = read(100, 50);
+        assert!(!chr1.apply(&record).unwrap()); // the read itself is clear
+        assert!(chr1.is_blacklisted(&(100..350))); // its fragment reaches the blacklist
+        assert!(!chr1.is_blacklisted(&(100..300)));
+    }
+
+    #[test]
+    fn fragment_length_bounds() {
+        let mut filter = Filter::default();
+        assert!(filter.accepts_fragment_length(None));
+        filter.set_minimum_fragment_length(Some(100)).set_maximum_fragment_length(Some(300));
+        assert!(filter.accepts_fragment_length(Some(100)));
+        assert!(filter.accepts_fragment_length(Some(300)));
+        assert!(!filter.accepts_fragment_length(Some(99)));
+        assert!(!filter.accepts_fragment_length(Some(301)));
+        assert!(!filter.accepts_fragment_length(None));
+    }
+
+    #[test]
+    fn template_length_is_only_checked_by_apply() {
+        let mut filter = Filter::default();
+        filter.set_maximum_fragment_length(Some(300)).set_pair_filter(PairFilter::Off);
+        let mut record = read(100, 50);
+        *record.flags_mut() = Flags::SEGMENTED;
+        *record.template_length_mut() = 5000; // a wrong TLEN
+        assert!(filter.apply(&record).unwrap());
+        assert!(!filter.apply_ignoring_template_length(&record).unwrap());
+    }
+
+    #[test]
+    fn low_mapping_quality_is_dropped_without_blacklist() {
+        let filter = Filter::default();
+        let mut record = read(100, 50);
+        *record.mapping_quality_mut() = MappingQuality::new(5);
+        assert!(filter.on_chromosome("chr1").apply(&record).unwrap());
+    }
+}
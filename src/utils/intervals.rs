@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::ops::Range;
+use std::path::Path;
+use noodles_core::Region;
+use crate::utils::coverage_bins::CoverageBins;
+
+/// Per-chromosome intervals, 0-based half-open, kept sorted and merged.
+#[derive(Clone, Debug, Default)]
+pub struct IntervalSet {
+    intervals: HashMap<String, Vec<Range<usize>>>,
+}
+
+impl IntervalSet {
+
+    /// Parses samtools style regions ("chr1", "chr1:1000-2000", 1-based inclusive).
+    /// A region without an end runs to the end of the chromosome.
+    pub fn from_region_strings(regions: &[String], chromosome_sizes: &HashMap<String, usize>) -> Result<Self, Box<dyn std::error::Error>>{
+        let mut set = IntervalSet::default();
+        for region_string in regions {
+            let region: Region = region_string.parse()
+                .map_err(|e| format!("invalid region '{}': {}", region_string, e))?;
+            let chromosome = region.name().to_string();
+            let chromosome_length = *chromosome_sizes.get(&chromosome)
+                .ok_or_else(|| format!("region '{}': chromosome {} is not in the alignment header", region_string, chromosome))?;
+            let interval = region.interval();
+            let start = interval.start().map(|p| p.get() - 1).unwrap_or(0);
+            let end = interval.end().map(|p| p.get()).unwrap_or(chromosome_length);
+            set.insert(chromosome, start..end);
+        }
+        set.normalize();
+        Ok(set)
+    }
+
+    /// Reads the first three columns of a BED file. Header, track and browser lines are skipped.
+    pub fn from_bed(bed_path: &Path) -> Result<Self, Box<dyn std::error::Error>>{
+        Self::read_bed(open_bed(bed_path)?, &bed_path.display().to_string())
+    }
+
+    fn read_bed<R: BufRead>(reader: R, source: &str) -> Result<Self, Box<dyn std::error::Error>>{
+        let mut set = IntervalSet::default();
+        for_each_bed_line(reader, source, |line| {
+            set.insert(line.chromosome.to_string(), line.interval);
+            Ok(())
+        })?;
+        set.normalize();
+        Ok(set)
+    }
+
+    pub fn insert(&mut self, chromosome: String, interval: Range<usize>){
+        self.intervals.entry(chromosome).or_default().push(interval);
+    }
+
+    /// Adds everything from `other`, result stays sorted and merged.
+    pub fn extend(&mut self, other: IntervalSet){
+        for (chromosome, intervals) in other.intervals {
+            self.intervals.entry(chromosome).or_default().extend(intervals);
+        }
+        self.normalize();
+    }
+
+    /// Drops empty intervals, sorts and merges overlapping or touching ones.
+    fn normalize(&mut self){
+        for intervals in self.intervals.values_mut() {
+            intervals.retain(|interval| interval.start < interval.end);
+            intervals.sort_by_key(|interval| interval.start);
+            let mut merged: Vec<Range<usize>> = Vec::with_capacity(intervals.len());
+            for interval in intervals.drain(..) {
+                match merged.last_mut() {
+                    Some(last) if interval.start <= last.end => last.end = last.end.max(interval.end),
+                    _ => merged.push(interval),
+                }
+            }
+            *intervals = merged;
+        }
+        self.intervals.retain(|_, intervals| !intervals.is_empty());
+    }
+
+    /// Checks every chromosome exists and clips intervals to the chromosome length.
+    pub fn validate(&mut self, chromosome_sizes: &HashMap<String, usize>) -> Result<(), Box<dyn std::error::Error>>{
+        let unknown = self.clip_to_chromosomes(chromosome_sizes);
+        if !unknown.is_empty() {
+            return Err(format!("chromosome {} is not in the alignment header", unknown.join(", ")).into());
+        }
+        Ok(())
+    }
+
+    /// Clips intervals to the chromosome length and drops chromosomes missing from `chromosome_sizes`.
+    /// Returns the dropped chromosome names, sorted.
+    pub fn clip_to_chromosomes(&mut self, chromosome_sizes: &HashMap<String, usize>) -> Vec<String>{
+        let mut unknown: Vec<String> = self.intervals.keys()
+            .filter(|chromosome| !chromosome_sizes.contains_key(*chromosome))
+            .cloned()
+            .collect();
+        unknown.sort();
+        self.intervals.retain(|chromosome, _| chromosome_sizes.contains_key(chromosome));
+        for (chromosome, intervals) in self.intervals.iter_mut() {
+            let chromosome_length = chromosome_sizes[chromosome];
+            for interval in intervals.iter_mut() {
+                interval.end = interval.end.min(chromosome_length);
+            }
+        }
+        self.normalize();
+        unknown
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Vec<Range<usize>>)>{
+        self.intervals.iter()
+    }
+
+    pub fn get(&self, chromosome: &str) -> &[Range<usize>]{
+        self.intervals.get(chromosome).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.intervals.is_empty()
+    }
+
+    pub fn total_length(&self) -> usize{
+        self.intervals.values().flatten().map(|interval| interval.end - interval.start).sum()
+    }
+
+    pub fn interval_count(&self) -> usize{
+        self.intervals.values().map(|intervals| intervals.len()).sum()
+    }
+}
+
+/// True if `interval` overlaps any of the sorted, merged `intervals`.
+pub fn overlaps_any(intervals: &[Range<usize>], interval: &Range<usize>) -> bool{
+    let first = intervals.partition_point(|other| other.end <= interval.start);
+    intervals.get(first).is_some_and(|other| other.start < interval.end)
+}
+
+/// Sets every bin of one chromosome touching `mask` (that chromosome's sorted intervals) to zero.
+pub fn zero_masked_bins<C: CoverageBins>(coverage_over_bins: &mut C, mask: &[Range<usize>], bin_size: usize){
+    for interval in mask {
+        let first_bin = interval.start / bin_size;
+        let last_bin = ((interval.end - 1) / bin_size).min(coverage_over_bins.bin_count().saturating_sub(1));
+        if first_bin < coverage_over_bins.bin_count() {
+            coverage_over_bins.clear_range(first_bin..last_bin + 1);
+        }
+    }
+}
+
+/// One data line of a BED file, 0-based half-open. `name` is the fourth column when there is one.
+struct BedLine<'a> {
+    chromosome: &'a str,
+    interval: Range<usize>,
+    name: Option<&'a str>,
+}
+
+/// Parses one BED line. Blank, comment, track and browser lines give None.
+fn parse_bed_line(line: &str) -> Result<Option<BedLine<'_>>, String>{
+    if line.trim().is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") {
+        return Ok(None);
+    }
+    let mut fields = line.split('\t');
+    let (Some(chromosome), Some(start), Some(end)) = (fields.next(), fields.next(), fields.next()) else {
+        return Err("expected at least 3 tab separated columns".to_string());
+    };
+    let start: usize = start.trim().parse().map_err(|e| format!("invalid start '{}': {}", start, e))?;
+    let end: usize = end.trim().parse().map_err(|e| format!("invalid end '{}': {}", end, e))?;
+    if start > end {
+        return Err(format!("start {} is after end {}", start, end));
+    }
+    let name = fields.next().map(str::trim).filter(|name| !name.is_empty());
+    Ok(Some(BedLine { chromosome, interval: start..end, name }))
+}
+
+fn open_bed(bed_path: &Path) -> Result<BufReader<std::fs::File>, Box<dyn std::error::Error>>{
+    Ok(BufReader::new(std::fs::File::open(bed_path)
+        .map_err(|e| format!("cannot open {}: {}", bed_path.display(), e))?))
+}
+
+/// Calls `f` for every data line. Errors from parsing or from `f` get the `source:line` prefix.
+fn for_each_bed_line<R: BufRead, F: FnMut(BedLine) -> Result<(), String>>(reader: R, source: &str, mut f: F) -> Result<(), Box<dyn std::error::Error>>{
+    for (line_number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if let Some(bed_line) = parse_bed_line(&line).map_err(|e| format!("{}:{}: {}", source, line_number + 1, e))? {
+            f(bed_line).map_err(|e| format!("{}:{}: {}", source, line_number + 1, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// One interval of `--bins-bed`, 0-based half-open. `name` is the BED name column when there is one.
+#[derive(Clone, Debug)]
+pub struct BedBin {
+    pub interval: Range<usize>,
+    pub name: Option<String>,
+}
+
+/// Variable-width bins read from a BED file. Unlike `IntervalSet` nothing is merged, every line stays its own bin,
+/// so within a chromosome the intervals have to be sorted and must not overlap.
+#[derive(Clone, Debug, Default)]
+pub struct BedBins {
+    bins: HashMap<String, Vec<BedBin>>,
+    lines: Vec<(String, usize)>, // chromosome and index into its bins of every line, in file order
+}
+
+impl BedBins {
+
+    /// Reads chromosome, start, end and the optional name column. Header, track and browser lines are skipped.
+    pub fn from_bed(bed_path: &Path) -> Result<Self, Box<dyn std::error::Error>>{
+        Self::read_bed(open_bed(bed_path)?, &bed_path.display().to_string())
+    }
+
+    fn read_bed<R: BufRead>(reader: R, source: &str) -> Result<Self, Box<dyn std::error::Error>>{
+        let mut bed_bins = BedBins::default();
+        for_each_bed_line(reader, source, |line| {
+            let BedLine { chromosome, interval, name } = line;
+            if interval.is_empty() {
+                return Err(format!("empty interval {}-{}", interval.start, interval.end));
+            }
+            let bins = bed_bins.bins.entry(chromosome.to_string()).or_default();
+            if let Some(previous) = bins.last() && interval.start < previous.interval.end {
+                return Err(format!("{}:{}-{} overlaps or comes before the previous interval, bins must be sorted (sort -k1,1 -k2,2n) and must not overlap",
+                    chromosome, interval.start, interval.end));
+            }
+            bed_bins.lines.push((chromosome.to_string(), bins.len()));
+            bins.push(BedBin { interval, name: name.map(str::to_string) });
+            Ok(())
+        })?;
+        if bed_bins.bins.is_empty() {
+            return Err(format!("{}: no intervals", source).into());
+        }
+        Ok(bed_bins)
+    }
+
+    /// Checks every chromosome exists and no interval runs past its end.
+    pub fn validate(&self, chromosome_sizes: &HashMap<String, usize>) -> Result<(), Box<dyn std::error::Error>>{
+        for (chromosome, bins) in &self.bins {
+            let chromosome_length = *chromosome_sizes.get(chromosome)
+                .ok_or_else(|| format!("--bins-bed: chromosome {} is not in the alignment header", chromosome))?;
+            if let Some(last) = bins.last() && last.interval.end > chromosome_length {
+                return Err(format!("--bins-bed: {}:{}-{} runs past the chromosome end ({} bp)", chromosome, last.interval.start, last.interval.end, chromosome_length).into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, chromosome: &str) -> &[BedBin]{
+        self.bins.get(chromosome).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn bin_count(&self) -> usize{
+        self.bins.values().map(|bins| bins.len()).sum()
+    }
+
+    /// Every bin with its chromosome and its index within the chromosome, in BED line order.
+    pub fn lines(&self) -> impl Iterator<Item = (&str, usize, &BedBin)>{
+        self.lines.iter().map(|(chromosome, bin_index)| (chromosome.as_str(), *bin_index, &self.bins[chromosome][*bin_index]))
+    }
+}
+
+/// Sets every `--bins-bed` bin of one chromosome overlapping `mask` to zero.
+pub fn zero_masked_bed_bins<C: CoverageBins>(coverage_over_bins: &mut C, bins: &[BedBin], mask: &[Range<usize>]){
+    for (bin_index, bin) in bins.iter().enumerate() {
+        if overlaps_any(mask, &bin.interval) {
+            coverage_over_bins.clear_range(bin_index..bin_index + 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interval_set_skips_header_lines_and_merges() {
+        let bed = "track name=peaks\nbrowser position chr1\n# comment\n\nchr1\t100\t200\nchr1\t150\t300\tpeak\nchr2\t0\t10\n";
+        let set = IntervalSet::read_bed(bed.as_bytes(), "test.bed").unwrap();
+        assert_eq!(set.get("chr1").to_vec(), vec![100..300]);
+        assert_eq!(set.get("chr2").to_vec(), vec![0..10]);
+    }
+
+    #[test]
+    fn start_after_end_is_an_error() {
+        let bed = "chr1\t100\t200\nchr1\t500\t400\n";
+        let error = IntervalSet::read_bed(bed.as_bytes(), "test.bed").unwrap_err().to_string();
+        assert_eq!(error, "test.bed:2: start 500 is after end 400");
+        assert!(BedBins::read_bed(bed.as_bytes(), "test.bed").is_err());
+    }
+
+    #[test]
+    fn missing_columns_are_an_error() {
+        let error = IntervalSet::read_bed("chr1\t100\n".as_bytes(), "test.bed").unwrap_err().to_string();
+        assert_eq!(error, "test.bed:1: expected at least 3 tab separated columns");
+    }
+
+    #[test]
+    fn bed_bins_keep_every_line_and_its_name() {
+        let bed = "track name=genes\nchr1\t0\t100\tgeneA\nchr1\t100\t250\nchr1\t400\t500\tgeneC\n";
+        let bed_bins = BedBins::read_bed(bed.as_bytes(), "test.bed").unwrap();
+        let bins = bed_bins.get("chr1");
+        assert_eq!(bins.len(), 3);
+        assert_eq!(bins[0].interval, 0..100);
+        assert_eq!(bins[0].name.as_deref(), Some("geneA"));
+        assert_eq!(bins[1].interval, 100..250);
+        assert_eq!(bins[1].name, None);
+        assert_eq!(bins[2].interval, 400..500);
+        assert_eq!(bed_bins.bin_count(), 3);
+    }
+
+    #[test]
+    fn bed_bins_remember_the_line_order() {
+        let bed = "chr2	0	100	a
+chr1	0	100	b
+chr2	200	300	c
+";
+        let bed_bins = BedBins::read_bed(bed.as_bytes(), "test.bed").unwrap();
+        let lines: Vec<(&str, usize, Option<&str>)> = bed_bins.lines().map(|(chromosome, bin_index, bin)| (chromosome, bin_index, bin.name.as_deref())).collect();
+        assert_eq!(lines, [("chr2", 0, Some("a")), ("chr1", 0, Some("b")), ("chr2", 1, Some("c"))]);
+    }
+
+    #[test]
+    fn bed_bins_reject_unsorted_overlapping_and_empty_intervals() {
+        let unsorted = "chr1\t300\t400\nchr1\t100\t200\n";
+        let overlapping = "chr1\t100\t300\nchr1\t200\t400\n";
+        let empty = "chr1\t100\t100\n";
+        for bed in [unsorted, overlapping, empty] {
+            assert!(BedBins::read_bed(bed.as_bytes(), "test.bed").is_err(), "{:?}", bed);
+        }
+        assert!(BedBins::read_bed("track name=x\n".as_bytes(), "test.bed").is_err());
+    }
+
+    #[test]
+    fn bed_bins_check_chromosome_ends() {
+        let bed_bins = BedBins::read_bed("chr1\t0\t100\n".as_bytes(), "test.bed").unwrap();
+        assert!(bed_bins.validate(&HashMap::from([("chr1".to_string(), 100)])).is_ok());
+        assert!(bed_bins.validate(&HashMap::from([("chr1".to_string(), 99)])).is_err());
+        assert!(bed_bins.validate(&HashMap::from([("chr2".to_string(), 100)])).is_err());
+    }
+}
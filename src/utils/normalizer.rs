@@ -1,68 +1,99 @@
+use crate::utils::coverage_bins::CoverageBins;
 
-use rayon::prelude::*;
+#[derive(Clone, Debug, PartialEq)]
+#[derive(clap::ValueEnum)]
+pub enum Normalization {
+    None,
+    Cpm,  // counts per million reads
+    Rpkm, // reads per kilobase per million reads
+    Rpgc, // reads per genomic content (1x coverage), needs effective genome size
+    Bpm,  // bins per million, like TPM over bins
+}
+
+impl Normalization {
+    pub fn needs_library_size(&self) -> bool {
+        matches!(self, Normalization::Cpm | Normalization::Rpkm | Normalization::Rpgc)
+    }
 
-pub fn cpm(coverage_over_bins_all_chromosomes: Vec<Vec<f64>>, total_read_count: u64) -> Result<Vec<Vec<f64>>, Box<dyn std::error::Error + Send + Sync>>{
-    
-    Ok(
-        coverage_over_bins_all_chromosomes.par_iter()
-        .map(|chr| {
-            chr.iter()
-                .map(|&count| count as f64 * 1_000_000.0 / total_read_count as f64)
-                .collect()
-        })
-        .collect()
-    )
-    
+    /// BPM divides by the sum of all bins, which is only known after a full pass.
+    pub fn needs_bin_total(&self) -> bool {
+        matches!(self, Normalization::Bpm)
+    }
 }
 
+/// Where the library size for cpm/rpkm/rpgc comes from.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(clap::ValueEnum)]
+pub enum LibrarySize {
+    Filtered, // reads (or fragments) that passed the filter during the coverage pass
+    Index,    // all records according to the index, minus blacklisted reads
+}
 
-pub fn rpkm(coverage_over_bins_all_chromosomes: Vec<Vec<f64>>, total_read_count: u64, bin_size: usize) -> Result<Vec<Vec<f64>>, Box<dyn std::error::Error + Send + Sync>>{
-    Ok(
-        coverage_over_bins_all_chromosomes.par_iter()
-        .map(|chr| {
-            chr.iter()
-                .map(|&count| (count as f64 * 1_000_000_000.0) / (total_read_count as f64 * bin_size as f64))
-                .collect()
-        })
-        .collect()
-    )
+/// Dispatches to the chosen method for one chromosome. `effective_genome_size` and `average_read_length` are only read for RPGC,
+/// `total_bins_count` (sum over all bins of the track, from a first pass) only for BPM.
+pub fn normalize<C: CoverageBins>(
+    coverage_over_bins: C,
+    method: &Normalization,
+    total_read_count: u64,
+    bin_size: usize,
+    effective_genome_size: Option<usize>,
+    average_read_length: Option<usize>,
+    total_bins_count: f64,
+) -> Result<C, Box<dyn std::error::Error + Send + Sync>>{
+    if method.needs_library_size() && total_read_count == 0 {
+        return Err(format!("cannot apply {:?} normalization: library size is 0 reads", method).into());
+    }
+    match method {
+        Normalization::None => Ok(coverage_over_bins),
+        Normalization::Cpm => cpm(coverage_over_bins, total_read_count),
+        Normalization::Rpkm => rpkm(coverage_over_bins, total_read_count, bin_size),
+        Normalization::Rpgc => {
+            let effective_genome_size = effective_genome_size.ok_or("rpgc normalization needs --effective-genome-size")?;
+            let average_read_length = average_read_length.ok_or("rpgc normalization needs an average read length")?;
+            rpgc(coverage_over_bins, total_read_count, effective_genome_size, average_read_length)
+        }
+        Normalization::Bpm => bpm(coverage_over_bins, total_bins_count),
+    }
 }
 
+pub fn cpm<C: CoverageBins>(mut coverage_over_bins: C, total_read_count: u64) -> Result<C, Box<dyn std::error::Error + Send + Sync>>{
+    coverage_over_bins.map_values(|count| count * 1_000_000.0 / total_read_count as f64);
+    Ok(coverage_over_bins)
+}
 
-pub fn rpgc(coverage_over_bins_all_chromosomes: Vec<Vec<f64>>, total_read_count: u64, effective_genome_size: usize, average_read_length: usize) -> Result<Vec<Vec<f64>>, Box<dyn std::error::Error + Send + Sync>>{
-    let scale = effective_genome_size as f64 / (total_read_count as f64 * average_read_length as f64);
-    Ok(
-        coverage_over_bins_all_chromosomes.par_iter()
-        .map(|chr| {
-            chr.iter()
-                .map(|&count| count as f64 * scale)
-                .collect()
-        })
-        .collect()
-    )
+
+pub fn rpkm<C: CoverageBins>(mut coverage_over_bins: C, total_read_count: u64, bin_size: usize) -> Result<C, Box<dyn std::error::Error + Send + Sync>>{
+    coverage_over_bins.map_values(|count| (count * 1_000_000_000.0) / (total_read_count as f64 * bin_size as f64));
+    Ok(coverage_over_bins)
 }
 
 
-pub fn bpm(coverage_over_bins_all_chromosomes: Vec<Vec<f64>>) -> Result<Vec<Vec<f64>>, Box<dyn std::error::Error + Send + Sync>>{
-    let total_bins_count = total_bins_count(&coverage_over_bins_all_chromosomes)?;
-    Ok(    
-        coverage_over_bins_all_chromosomes.par_iter()
-        .map(|chr| {
-            chr.iter()
-                .map(|&count| count as f64 * 1_000_000.0 / total_bins_count as f64)
-                .collect()
-        })
-        .collect()
-    )
+/// RPKM for variable-width bins, each bin divided by its own width in `bin_widths`.
+pub fn rpkm_by_width<C: CoverageBins>(mut coverage_over_bins: C, total_read_count: u64, bin_widths: &[usize]) -> Result<C, Box<dyn std::error::Error + Send + Sync>>{
+    if total_read_count == 0 {
+        return Err("cannot apply Rpkm normalization: library size is 0 reads".into());
+    }
+    for (bin, bin_width) in bin_widths.iter().enumerate() {
+        let count = coverage_over_bins.get(bin);
+        if count != 0.0 {
+            coverage_over_bins.set(bin, (count * 1_000_000_000.0) / (total_read_count as f64 * *bin_width as f64));
+        }
+    }
+    Ok(coverage_over_bins)
 }
 
-fn total_bins_count(coverage_over_bins_all_chromosomes: &Vec<Vec<f64>>) -> Result<f64, Box<dyn std::error::Error + Send + Sync>>{
 
-    Ok(
-        coverage_over_bins_all_chromosomes
-        .iter()
-        .flatten()
-        .sum()
-    )
+pub fn rpgc<C: CoverageBins>(mut coverage_over_bins: C, total_read_count: u64, effective_genome_size: usize, average_read_length: usize) -> Result<C, Box<dyn std::error::Error + Send + Sync>>{
+    let scale = effective_genome_size as f64 / (total_read_count as f64 * average_read_length as f64);
+    coverage_over_bins.map_values(|count| count * scale);
+    Ok(coverage_over_bins)
+}
+
 
+pub fn bpm<C: CoverageBins>(mut coverage_over_bins: C, total_bins_count: f64) -> Result<C, Box<dyn std::error::Error + Send + Sync>>{
+    if total_bins_count == 0.0 {
+        return Err("cannot apply bpm normalization: all bins are empty".into());
+    }
+    coverage_over_bins.map_values(|count| count * 1_000_000.0 / total_bins_count);
+    Ok(coverage_over_bins)
 }
@@ -0,0 +1,262 @@
+use std::{collections::HashMap, fs::File, io::{self, BufWriter, Write}, iter::Peekable, ops::Range, path::{Path, PathBuf}};
+use crate::utils::coverage_bins::CoverageBins;
+use crate::utils::intervals::{BedBins, IntervalSet};
+use bigtools::{BigWigWrite, Value};
+use bigtools::beddata::BedParserStreamingIterator;
+
+#[derive(Clone, Debug, PartialEq)]
+#[derive(clap::ValueEnum)]
+pub enum OutputFormat {
+    Bigwig,
+    Bedgraph,
+    Tsv, // count table of the --bins-bed intervals, zeros included
+}
+
+/// Where bin `i` of a chromosome lies: fixed-size tiles, or the `i`-th `--bins-bed` interval.
+#[derive(Clone, Copy)]
+pub enum BinLayout<'a> {
+    Tiles { bin_size: usize, merge_bins: bool, regions: Option<&'a IntervalSet> },
+    Bed(&'a BedBins),
+}
+
+/// Turns one chromosome's non-zero runs of bins (see `CoverageBins::into_runs`) into 0-based half-open intervals, one per bin.
+/// The last bin is clipped to the chromosome end. With `merge` adjacent bins holding the same value become one interval.
+pub struct BinIntervals<R: Iterator<Item = (Range<usize>, f64)>> {
+    runs: Peekable<R>,
+    bin_size: usize,
+    chromosome_size: usize,
+    merge: bool,
+    pending: Option<(Range<usize>, f64)>, // rest of a run that is written bin by bin
+}
+
+impl<R: Iterator<Item = (Range<usize>, f64)>> BinIntervals<R> {
+    pub fn new(runs: R, bin_size: usize, chromosome_size: usize, merge: bool) -> Self {
+        BinIntervals { runs: runs.peekable(), bin_size, chromosome_size, merge, pending: None }
+    }
+}
+
+impl<R: Iterator<Item = (Range<usize>, f64)>> Iterator for BinIntervals<R> {
+    type Item = (usize, usize, f64); // start, end, value
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (mut bins, value) = match self.pending.take() {
+            Some(run) => run,
+            None => self.runs.next()?,
+        };
+        if self.merge {
+            while let Some((next_bins, next_value)) = self.runs.peek() && next_bins.start == bins.end && *next_value == value {
+                bins.end = next_bins.end;
+                self.runs.next();
+            }
+        }else if bins.len() > 1 {
+            self.pending = Some((bins.start + 1..bins.end, value));
+            bins.end = bins.start + 1;
+        }
+        let start = bins.start * self.bin_size;
+        let end = std::cmp::min(bins.end * self.bin_size, self.chromosome_size);
+        if start >= end { // bin past the chromosome end, only happens when length is a multiple of bin size
+            return None;
+        }
+        Some((start, end, value))
+    }
+}
+
+/// Cuts intervals down to `regions` (sorted, merged). An interval overlapping several regions is split.
+pub fn clip_to_regions<'a, I>(intervals: I, regions: &'a [Range<usize>]) -> impl Iterator<Item = (usize, usize, f64)> + 'a
+where I: Iterator<Item = (usize, usize, f64)> + 'a
+{
+    intervals.flat_map(move |(start, end, value)| {
+        let first = regions.partition_point(|region| region.end <= start);
+        regions[first..].iter()
+            .take_while(move |region| region.start < end)
+            .map(move |region| (start.max(region.start), end.min(region.end), value))
+    })
+}
+
+/// Intervals of one chromosome, restricted to `regions` when given.
+fn chromosome_intervals<'a, C: CoverageBins>(chrom_name: &str, bins: C, chrom_size: usize, layout: BinLayout<'a>) -> Box<dyn Iterator<Item = (usize, usize, f64)> + 'a>{
+    match layout {
+        BinLayout::Tiles { bin_size, merge_bins, regions } => {
+            let intervals = BinIntervals::new(bins.into_runs(), bin_size, chrom_size, merge_bins);
+            match regions {
+                Some(regions) => Box::new(clip_to_regions(intervals, regions.get(chrom_name))),
+                None => Box::new(intervals),
+            }
+        }
+        BinLayout::Bed(bed_bins) => {
+            let chromosome_bins = bed_bins.get(chrom_name);
+            Box::new(bins.into_runs()
+                .flat_map(|(bins, value)| bins.map(move |bin| (bin, value)))
+                .map(move |(bin, value)| (chromosome_bins[bin].interval.start, chromosome_bins[bin].interval.end, value)))
+        }
+    }
+}
+
+/// One chromosome on its way to the writer: name, length and bins.
+pub type ChromosomeBins<C> = (String, usize, C);
+
+/// Header indices in the order the format is written: sorted by name for BigWig (bigtools needs it), header order for bedGraph.
+pub fn chromosome_write_order(output_format: &OutputFormat, chromosomes: &[(String, usize)]) -> Vec<usize>{
+    let mut order: Vec<usize> = (0..chromosomes.len()).collect();
+    if *output_format == OutputFormat::Bigwig {
+        order.sort_by(|&a, &b| chromosomes[a].0.cmp(&chromosomes[b].0));
+    }
+    order
+}
+
+/// `coverage_by_chromosome` has to come in name order, see `chromosome_write_order`. It is consumed while writing,
+/// so only the chromosomes in flight are in memory. `chromosomes` are all (name, length) pairs, for the file header.
+pub fn write_bigwig_output<B, C>(output: PathBuf, coverage_by_chromosome: C, chromosomes: &[(String, usize)], layout: BinLayout, threads: usize) -> Result<(), Box<dyn std::error::Error>>
+where B: CoverageBins, C: Iterator<Item = ChromosomeBins<B>>
+{
+    
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads)
+        .enable_all()
+        .build()?;
+
+    let chrom_map: HashMap<String, u32> = chromosomes
+        .iter()
+        .map(|(name, size)| Ok((name.to_string(), bigwig_coordinate(*size).map_err(|e| format!("chromosome {}: {}", name, e))?)))
+        .collect::<Result<_, String>>()?;
+
+    let first_chromosome = chromosomes.iter().map(|(name, _)| name).min().cloned().ok_or("no chromosomes to write")?;
+    let mut values_iter = coverage_by_chromosome
+        .flat_map(|(chrom_name, chrom_size, bins)|
+        {
+            chromosome_intervals(&chrom_name, bins, chrom_size, layout)
+                .map(move |(start, end, val)| {
+                    Ok((chrom_name.clone(), Value { start: bigwig_coordinate(start)?, end: bigwig_coordinate(end)?, value: val as f32 }))
+                })
+        })
+        .peekable();
+    let values_iter: Box<dyn Iterator<Item = io::Result<(String, Value)>>> = if values_iter.peek().is_some(){
+        Box::new(values_iter)
+    }else{ // bigtools refuses empty input, a single zero keeps the file valid (e.g. an empty strand)
+        eprintln!("No coverage to write, {} only holds a zero value", output.display());
+        Box::new(std::iter::once(Ok((first_chromosome, Value { start: 0, end: 1, value: 0.0 }))))
+    };
+
+    let data_source = BedParserStreamingIterator::wrap_iter(values_iter, false);
+    let writer = BigWigWrite::create_file(output.to_string_lossy().to_string(), chrom_map)?;
+    writer.write(data_source, runtime)?;
+
+    Ok(())
+}
+
+/// BigWig stores coordinates as u32, larger positions are an error instead of wrapping around.
+fn bigwig_coordinate(position: usize) -> io::Result<u32>{
+    u32::try_from(position).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("position {} is past the largest BigWig coordinate {}", position, u32::MAX)))
+}
+
+/// Path of one track of a multi-track output: `sample.bw` becomes `sample.fwd.bw`.
+pub fn track_output_path(output: &Path, track_name: &str) -> PathBuf{
+    let stem = output.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let file_name = match output.extension(){
+        Some(extension) => format!("{}.{}.{}", stem, track_name, extension.to_string_lossy()),
+        None => format!("{}.{}", stem, track_name),
+    };
+    output.with_file_name(file_name)
+}
+
+/// Opens the output for text formats. "-" means stdout so the result can be piped.
+pub fn open_text_output(output: &Path) -> Result<Box<dyn Write>, Box<dyn std::error::Error>>{
+    if output.as_os_str() == "-" {
+        Ok(Box::new(BufWriter::new(io::stdout().lock())))
+    }else{
+        Ok(Box::new(BufWriter::new(File::create(output)?)))
+    }
+}
+
+/// Streams bedGraph lines in the order chromosomes arrive, zero bins are left out.
+pub fn write_bedgraph_output<B, C>(output: &Path, coverage_by_chromosome: C, layout: BinLayout) -> Result<(), Box<dyn std::error::Error>>
+where B: CoverageBins, C: Iterator<Item = ChromosomeBins<B>>
+{
+    let mut writer = open_text_output(output)?;
+    for (chrom_name, chrom_size, coverage_over_bins) in coverage_by_chromosome {
+        for (start, end, value) in chromosome_intervals(&chrom_name, coverage_over_bins, chrom_size, layout) {
+            writeln!(writer, "{}\t{}\t{}\t{}", chrom_name, start, end, value)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// One line per `--bins-bed` interval with a header, in BED line order, empty intervals included so the table lines up with the BED.
+/// Chromosomes are taken from `coverage_by_chromosome` as the lines need them, those counted ahead wait in memory.
+/// Intervals without a name are named `chrom:start-end`.
+pub fn write_tsv_output<B, C>(output: &Path, mut coverage_by_chromosome: C, bed_bins: &BedBins) -> Result<(), Box<dyn std::error::Error>>
+where B: CoverageBins, C: Iterator<Item = ChromosomeBins<B>>
+{
+    let mut writer = open_text_output(output)?;
+    writeln!(writer, "chrom\tstart\tend\tname\tvalue")?;
+    let mut counted: HashMap<String, B> = HashMap::new();
+    for (chrom_name, bin_index, bin) in bed_bins.lines() {
+        while !counted.contains_key(chrom_name) {
+            let Some((name, _, coverage_over_bins)) = coverage_by_chromosome.next() else {
+                return Err(format!("no coverage for {}", chrom_name).into());
+            };
+            counted.insert(name, coverage_over_bins);
+        }
+        let name = bin.name.clone().unwrap_or_else(|| format!("{}:{}-{}", chrom_name, bin.interval.start, bin.interval.end));
+        writeln!(writer, "{}\t{}\t{}\t{}\t{}", chrom_name, bin.interval.start, bin.interval.end, name, counted[chrom_name].get(bin_index))?;
+    }
+    coverage_by_chromosome.for_each(drop); // chromosomes without bins, so the coverage pass does not see a stopped writer
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::coverage_bins::DenseBins;
+
+    /// Dense bins holding `values`.
+    fn bins(values: &[f64]) -> DenseBins {
+        let mut bins = DenseBins::with_bin_count(values.len());
+        for (bin, value) in values.iter().enumerate() {
+            bins.set(bin, *value);
+        }
+        bins
+    }
+
+    fn bedgraph(values: &[f64], layout: BinLayout) -> String {
+        let path = std::env::temp_dir().join(format!("bamcowig-{}-output.bedgraph", std::process::id()));
+        write_bedgraph_output(&path, [("chr1".to_string(), 250, bins(values))].into_iter(), layout).unwrap();
+        let bedgraph = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(path);
+        bedgraph
+    }
+
+    #[test]
+    fn bedgraph_lines_are_0_based_half_open() {
+        let tiles = BinLayout::Tiles { bin_size: 100, merge_bins: false, regions: None };
+        // zero bins left out, the last bin clipped to the chromosome end
+        assert_eq!(bedgraph(&[1.0, 0.0, 2.0], tiles), "chr1\t0\t100\t1\nchr1\t200\t250\t2\n");
+        assert_eq!(bedgraph(&[1.0, 1.0, 0.0], tiles), "chr1\t0\t100\t1\nchr1\t100\t200\t1\n");
+        let merged = BinLayout::Tiles { bin_size: 100, merge_bins: true, regions: None };
+        assert_eq!(bedgraph(&[1.0, 1.0, 0.0], merged), "chr1\t0\t200\t1\n");
+        let mut regions = IntervalSet::default();
+        regions.insert("chr1".to_string(), 50..220);
+        let clipped = BinLayout::Tiles { bin_size: 100, merge_bins: false, regions: Some(&regions) };
+        assert_eq!(bedgraph(&[1.0, 3.0, 2.0], clipped), "chr1\t50\t100\t1\nchr1\t100\t200\t3\nchr1\t200\t220\t2\n");
+    }
+
+    #[test]
+    fn tsv_rows_follow_the_bed_lines() {
+        let path = |name: &str| std::env::temp_dir().join(format!("bamcowig-{}-{}", std::process::id(), name));
+        std::fs::write(path("tsv-order.bed"), "chr2\t0\t100\ta\nchr1\t0\t100\nchr2\t200\t300\tc\n").unwrap();
+        let bed_bins = BedBins::from_bed(&path("tsv-order.bed")).unwrap();
+        // header order, with a chromosome the BED has no bins on
+        let coverage_by_chromosome = vec![
+            ("chr1".to_string(), 1000, bins(&[1.0])),
+            ("chr2".to_string(), 1000, bins(&[2.0, 3.0])),
+            ("chr3".to_string(), 1000, bins(&[])),
+        ];
+        write_tsv_output(&path("tsv-order.tsv"), coverage_by_chromosome.into_iter(), &bed_bins).unwrap();
+        assert_eq!(std::fs::read_to_string(path("tsv-order.tsv")).unwrap(),
+            "chrom\tstart\tend\tname\tvalue\nchr2\t0\t100\ta\t2\nchr1\t0\t100\tchr1:0-100\t1\nchr2\t200\t300\tc\t3\n");
+        let _ = std::fs::remove_file(path("tsv-order.bed"));
+        let _ = std::fs::remove_file(path("tsv-order.tsv"));
+    }
+}